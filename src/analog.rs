@@ -0,0 +1,16 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_hal::{
+    Blocking,
+    analog::adc::{Adc, AdcChannel, AdcPin},
+    peripherals::ADC1,
+};
+
+/// ADC1 shared between the tasks sampling its pins.
+pub type SharedAdc = Mutex<CriticalSectionRawMutex, RefCell<Adc<'static, ADC1<'static>, Blocking>>>;
+
+/// Takes a single reading of `pin`, or `None` if the conversion failed.
+pub fn read<PIN: AdcChannel>(adc: &SharedAdc, pin: &mut AdcPin<PIN, ADC1<'static>>) -> Option<u16> {
+    adc.lock(|adc| nb::block!(adc.borrow_mut().read_oneshot(pin)).ok())
+}
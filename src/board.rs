@@ -0,0 +1,121 @@
+use esp_hal::gpio::AnyPin;
+
+use crate::{config::parse_u32, output::CHANNEL_COUNT};
+
+/// Pins the optional features are fixed to, which the configurable pins can't use while
+/// the feature is enabled.
+const FEATURE_GPIOS: [(bool, u8); 9] = [
+    (cfg!(feature = "knob"), 1),
+    (cfg!(feature = "supply-sense"), 2),
+    (cfg!(feature = "status-led"), 15),
+    (cfg!(feature = "kill-switch"), 16),
+    (cfg!(feature = "uart-bridge"), 19),
+    (cfg!(feature = "uart-bridge"), 20),
+    (cfg!(feature = "encoder"), 21),
+    (cfg!(feature = "encoder"), 22),
+    (cfg!(feature = "standby-relay"), 23),
+];
+/// USB-Serial-JTAG, which the firmware is flashed and logged over.
+const RESERVED_GPIOS: [u8; 2] = [12, 13];
+
+const fn gpio(value: &str) -> u8 {
+    let gpio = parse_u32(value);
+    assert!(gpio <= 23, "GPIOs must be GPIO0 to GPIO23");
+
+    gpio as u8
+}
+
+/// An empty value means the board doesn't have the pin.
+const fn optional_gpio(value: &str) -> Option<u8> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(gpio(value))
+    }
+}
+
+/// The GPIO of each LEDC channel, just `PWM_GPIO` unless the light is an RGB or RGBW
+/// strip.
+pub const PWM_GPIOS: [u8; CHANNEL_COUNT] = {
+    let all = [
+        gpio(env!("PWM_GPIO")),
+        gpio(env!("PWM_GREEN_GPIO")),
+        gpio(env!("PWM_BLUE_GPIO")),
+        gpio(env!("PWM_WHITE_GPIO")),
+    ];
+
+    let mut gpios = [0; CHANNEL_COUNT];
+    let mut i = 0;
+    while i < CHANNEL_COUNT {
+        gpios[i] = all[i];
+        i += 1;
+    }
+    gpios
+};
+/// Powers the RF switch, defaulting to the XIAO ESP32C6's.
+pub const ANTENNA_POWER_GPIO: Option<u8> = optional_gpio(env!("ANTENNA_POWER_GPIO"));
+/// Selects the internal antenna when low.
+pub const ANTENNA_SELECT_GPIO: Option<u8> = optional_gpio(env!("ANTENNA_SELECT_GPIO"));
+/// Defaults to the BOOT button on the XIAO ESP32C6.
+#[cfg_attr(not(feature = "button"), allow(dead_code))]
+pub const BUTTON_GPIO: u8 = gpio(env!("BUTTON_GPIO"));
+
+const _: () = {
+    let mut configured = [
+        ANTENNA_POWER_GPIO,
+        ANTENNA_SELECT_GPIO,
+        None,
+        None,
+        None,
+        None,
+        None,
+    ];
+    if cfg!(feature = "button") {
+        configured[2] = Some(BUTTON_GPIO);
+    }
+    let mut i = 0;
+    while i < CHANNEL_COUNT {
+        configured[3 + i] = Some(PWM_GPIOS[i]);
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < configured.len() {
+        if let Some(gpio) = configured[i] {
+            let mut j = 0;
+            while j < FEATURE_GPIOS.len() {
+                let (enabled, feature_gpio) = FEATURE_GPIOS[j];
+                assert!(
+                    !enabled || gpio != feature_gpio,
+                    "Configured GPIO is already used by an enabled feature"
+                );
+                j += 1;
+            }
+
+            let mut j = 0;
+            while j < RESERVED_GPIOS.len() {
+                assert!(
+                    gpio != RESERVED_GPIOS[j],
+                    "Configured GPIO is reserved for USB-Serial-JTAG"
+                );
+                j += 1;
+            }
+
+            let mut j = i + 1;
+            while j < configured.len() {
+                if let Some(other) = configured[j] {
+                    assert!(gpio != other, "The same GPIO is configured twice");
+                }
+                j += 1;
+            }
+        }
+        i += 1;
+    }
+};
+
+/// Takes a configured pin.
+pub fn pin(gpio: u8) -> AnyPin<'static> {
+    // SAFETY: the configured pins are checked against each other and the pins the
+    // firmware takes from `Peripherals`, so nothing else drives them.
+    unsafe { AnyPin::steal(gpio) }
+}
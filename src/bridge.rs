@@ -0,0 +1,112 @@
+use anyhow::anyhow;
+use defmt::{info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use esp_hal::{Async, uart::Uart};
+
+use crate::{
+    config::parse_u32,
+    light::{self, LocalCommand},
+    log_error,
+};
+
+pub const BAUD_RATE: u32 = parse_u32(env!("BRIDGE_BAUD_RATE"));
+
+/// Frames are `[FRAME_START, kind, value, checksum]`, where the checksum is the XOR of
+/// the kind and value.
+const FRAME_START: u8 = 0xa5;
+const FRAME_LEN: usize = 4;
+/// Brightness for the co-processor to fade to.
+const KIND_SET_BRIGHTNESS: u8 = 0x01;
+/// Brightness the co-processor is at, sent when its own controls change it.
+const KIND_STATUS: u8 = 0x02;
+
+static TARGET: Signal<CriticalSectionRawMutex, u32> = Signal::new();
+
+/// Passes a brightness target on to the co-processor.
+pub fn forward(brightness: u32) {
+    TARGET.signal(brightness);
+}
+
+fn encode(kind: u8, value: u8) -> [u8; FRAME_LEN] {
+    [FRAME_START, kind, value, kind ^ value]
+}
+
+/// Reassembles frames from the serial stream, resynchronising on the start byte.
+struct FrameReader {
+    frame: [u8; FRAME_LEN],
+    len: usize,
+}
+
+impl FrameReader {
+    fn push(&mut self, byte: u8) -> Option<(u8, u8)> {
+        if self.len == 0 && byte != FRAME_START {
+            return None;
+        }
+        self.frame[self.len] = byte;
+        self.len += 1;
+
+        if self.len < FRAME_LEN {
+            return None;
+        }
+        self.len = 0;
+
+        let [_, kind, value, checksum] = self.frame;
+        if kind ^ value != checksum {
+            warn!("Dropping bridge frame with bad checksum");
+            return None;
+        }
+
+        Some((kind, value))
+    }
+}
+
+/// Relays brightness targets to a co-processor doing the actual dimming, and reports
+/// changes it makes on its own back to the light task so the server hears about them.
+#[embassy_executor::task]
+pub async fn bridge_task(uart: Uart<'static, Async>) {
+    let (mut rx, mut tx) = uart.split();
+
+    let mut reader = FrameReader {
+        frame: [0; FRAME_LEN],
+        len: 0,
+    };
+    let mut last_sent = None;
+    let mut buf = [0; 16];
+
+    loop {
+        match select(TARGET.wait(), rx.read_async(&mut buf)).await {
+            Either::First(brightness) => {
+                // Skip echoing back a brightness the co-processor just reported
+                if last_sent == Some(brightness) {
+                    continue;
+                }
+
+                let frame = encode(KIND_SET_BRIGHTNESS, brightness as u8);
+                if let Err(err) = tx.write_async(&frame).await {
+                    log_error(&anyhow!("{:?}", err).context("Failed to write to co-processor"));
+                    continue;
+                }
+                last_sent = Some(brightness);
+            }
+            Either::Second(Ok(len)) => {
+                for &byte in &buf[..len] {
+                    match reader.push(byte) {
+                        Some((KIND_STATUS, value)) => {
+                            let brightness = (value as u32).min(100);
+                            info!("Co-processor reported brightness [{}]", brightness);
+
+                            last_sent = Some(brightness);
+                            light::send_local(LocalCommand::SetBrightness(brightness));
+                        }
+                        Some((kind, _)) => warn!("Unknown bridge frame kind {}", kind),
+                        None => {}
+                    }
+                }
+            }
+            Either::Second(Err(err)) => {
+                log_error(&anyhow!("{:?}", err).context("Failed to read from co-processor"))
+            }
+        }
+    }
+}
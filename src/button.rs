@@ -0,0 +1,82 @@
+use embassy_time::{Duration, Timer, with_timeout};
+use esp_hal::gpio::{Input, InputConfig, Pull};
+
+use crate::{
+    board,
+    config::parse_u32,
+    gesture::{self, Gesture},
+};
+
+const DEBOUNCE: Duration = Duration::from_millis(20);
+/// Presses held longer than this ramp the brightness instead of toggling the light.
+const HOLD_THRESHOLD: Duration = Duration::from_millis(400);
+/// How long after releasing the button another press counts towards the same gesture.
+const CLICK_GAP: Duration = Duration::from_millis(300);
+/// How long the button has to be held to factory reset, `None` when disabled.
+const FACTORY_RESET_HOLD: Option<Duration> = match parse_u32(env!("BUTTON_FACTORY_RESET_HOLD_S")) {
+    0 => None,
+    secs => Some(Duration::from_secs(secs as u64)),
+};
+
+pub fn button_input() -> Input<'static> {
+    Input::new(
+        board::pin(board::BUTTON_GPIO),
+        InputConfig::default().with_pull(Pull::Up),
+    )
+}
+
+async fn press(button: &mut Input<'static>) {
+    loop {
+        button.wait_for_falling_edge().await;
+        Timer::after(DEBOUNCE).await;
+        if button.is_low() {
+            return;
+        }
+    }
+}
+
+async fn release(button: &mut Input<'static>) {
+    button.wait_for_high().await;
+    Timer::after(DEBOUNCE).await;
+}
+
+/// Turns presses of an active-low push button into gestures: clicks, which are counted
+/// while they keep coming within `CLICK_GAP`, and holds.
+#[embassy_executor::task]
+pub async fn button_task(mut button: Input<'static>) {
+    loop {
+        press(&mut button).await;
+
+        if with_timeout(HOLD_THRESHOLD, release(&mut button))
+            .await
+            .is_err()
+        {
+            gesture::dispatch(Gesture::HoldStart);
+
+            let long_hold = match FACTORY_RESET_HOLD {
+                Some(hold) => with_timeout(hold - HOLD_THRESHOLD, release(&mut button))
+                    .await
+                    .is_err(),
+                None => {
+                    release(&mut button).await;
+                    false
+                }
+            };
+
+            gesture::dispatch(Gesture::HoldEnd);
+            if long_hold {
+                gesture::dispatch(Gesture::LongHold);
+                release(&mut button).await;
+            }
+            continue;
+        }
+
+        let mut clicks = 1;
+        while with_timeout(CLICK_GAP, press(&mut button)).await.is_ok() {
+            release(&mut button).await;
+            clicks += 1;
+        }
+
+        gesture::dispatch(Gesture::Clicks(clicks));
+    }
+}
@@ -0,0 +1,339 @@
+use core::net::Ipv4Addr;
+
+use alloc::{string::String, vec, vec::Vec};
+use anyhow::{Result, anyhow, bail};
+use defmt::info;
+use embassy_net::Ipv4Cidr;
+use embassy_sync::once_lock::OnceLock;
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+
+use crate::{log_error, partition::Partition, storage::checksum};
+
+/// Parses a decimal number from a compile-time environment variable.
+pub const fn parse_u32(value: &str) -> u32 {
+    parse_digits(value.as_bytes())
+}
+
+/// Like `parse_u32`, but also accepting a leading `-`.
+#[cfg_attr(not(feature = "sntp"), allow(dead_code))]
+pub const fn parse_i32(value: &str) -> i32 {
+    match value.as_bytes() {
+        [b'-', digits @ ..] => -(parse_digits(digits) as i32),
+        digits => parse_digits(digits) as i32,
+    }
+}
+
+const fn parse_digits(bytes: &[u8]) -> u32 {
+    assert!(
+        !bytes.is_empty(),
+        "Expected a number but got an empty string"
+    );
+
+    let mut result: u32 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "Expected a decimal number");
+        result = result * 10 + (bytes[i] - b'0') as u32;
+        i += 1;
+    }
+
+    result
+}
+
+pub const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+
+    true
+}
+
+pub const HEAP_SIZE: usize = parse_u32(env!("HEAP_SIZE_KB")) as usize * 1024;
+
+/// Whether to get an address over DHCP, falling back to `IP_CIDR` if it is set.
+const USE_DHCP: bool = {
+    let mode = env!("IP_MODE");
+    if str_eq(mode, "dhcp") {
+        true
+    } else if str_eq(mode, "static") {
+        false
+    } else {
+        panic!("IP_MODE must be either dhcp or static")
+    }
+};
+
+const RUNTIME_CONFIG_MAGIC: [u8; 4] = *b"DCFG";
+/// Version 2 added the group ids and version 3 the build hash, older records are
+/// imported again from the build.
+const RUNTIME_CONFIG_VERSION: u8 = 3;
+/// Magic, version, build hash and body length.
+const RUNTIME_CONFIG_HEADER_LEN: usize = 11;
+const RUNTIME_CONFIG_MAX_LEN: usize = 1024;
+
+const RUNTIME_CONFIG_PARTITION: Partition = Partition {
+    label: "devctl_cfg",
+    subtype: 0x40,
+    len: RUNTIME_CONFIG_MAX_LEN,
+};
+
+static RUNTIME_CONFIG: OnceLock<RuntimeConfig> = OnceLock::new();
+
+/// Per-unit configuration kept in flash.
+///
+/// This is a boot-time import, not a store that can be changed at runtime: the build's
+/// environment variables are copied into the `devctl_cfg` partition on the first boot,
+/// along with a hash of them. The copy is used until a factory reset erases it or a
+/// build with different values is flashed, which is imported in its place.
+pub struct RuntimeConfig {
+    pub device_id: String,
+    pub wifi_ssid: String,
+    pub wifi_password: String,
+    pub use_dhcp: bool,
+    /// Static address, which is only a fallback when using DHCP.
+    pub ip_cidr: Option<Ipv4Cidr>,
+    pub dns_server: Option<Ipv4Addr>,
+    /// `host:port`, or empty to discover the server over mDNS.
+    pub server_addr: String,
+    /// PKCS#8 DER.
+    pub private_key: Vec<u8>,
+    /// SPKI DER.
+    pub server_public_key: Vec<u8>,
+    /// Groups the device also takes commands for, besides its own device id.
+    pub group_ids: Vec<String>,
+}
+
+/// The configuration loaded at boot by `load_runtime`.
+pub fn runtime() -> &'static RuntimeConfig {
+    RUNTIME_CONFIG
+        .try_get()
+        .expect("Runtime config used before it was loaded")
+}
+
+/// Loads the runtime config from flash, importing the build defaults if there is none.
+pub fn load_runtime(flash: &mut FlashStorage<'static>) {
+    let config = match RUNTIME_CONFIG_PARTITION.find(flash) {
+        Ok(offset) => load_or_import(flash, offset),
+        // Without its partition the build defaults still run, they just aren't kept
+        Err(err) => {
+            log_error(&err.context("Failed to find runtime config partition"));
+            RuntimeConfig::from_env()
+        }
+    };
+
+    if RUNTIME_CONFIG.init(config).is_err() {
+        panic!("Runtime config loaded twice");
+    }
+}
+
+fn load_or_import(flash: &mut FlashStorage<'static>, offset: u32) -> RuntimeConfig {
+    let build = RuntimeConfig::from_env();
+    let build_hash = checksum(&build.encode_body());
+
+    let config = RuntimeConfig::load(flash, offset, build_hash).unwrap_or_else(|err| {
+        log_error(&err.context("Failed to load runtime config"));
+        None
+    });
+
+    match config {
+        Some(config) => config,
+        None => {
+            info!("No runtime config in flash from this build, importing build defaults");
+            if let Err(err) = build.save(flash, offset, build_hash) {
+                log_error(&err.context("Failed to save runtime config"));
+            }
+            build
+        }
+    }
+}
+
+/// Erases the runtime config, so the build defaults are imported again on next boot.
+pub fn erase_runtime(flash: &mut FlashStorage<'static>) -> Result<()> {
+    let offset = RUNTIME_CONFIG_PARTITION.find(flash)?;
+    flash
+        .write(offset, &[0xff; RUNTIME_CONFIG_HEADER_LEN])
+        .map_err(|err| anyhow!("{:?}", err))
+}
+
+fn push_field(record: &mut Vec<u8>, field: &[u8]) {
+    record.extend_from_slice(&(field.len() as u16).to_le_bytes());
+    record.extend_from_slice(field);
+}
+
+/// Reads length-prefixed fields out of a record body.
+struct FieldReader<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> FieldReader<'a> {
+    fn next(&mut self) -> Result<&'a [u8]> {
+        let Some((len, rest)) = self.body.split_first_chunk::<2>() else {
+            bail!("Runtime config record is truncated");
+        };
+        let len = u16::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            bail!("Runtime config record is truncated");
+        }
+
+        let (field, rest) = rest.split_at(len);
+        self.body = rest;
+        Ok(field)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(core::str::from_utf8(self.next()?)
+            .map_err(|err| anyhow!("{:?}", err))?
+            .into())
+    }
+}
+
+impl RuntimeConfig {
+    fn from_env() -> Self {
+        Self {
+            device_id: env!("DEVICE_ID").into(),
+            wifi_ssid: env!("WIFI_SSID").into(),
+            wifi_password: env!("WIFI_PASSWORD").into(),
+            use_dhcp: USE_DHCP,
+            ip_cidr: match env!("IP_CIDR") {
+                "" => None,
+                cidr => Some(cidr.parse().expect("Invalid IP_CIDR")),
+            },
+            dns_server: match env!("DNS_SERVER") {
+                "" => None,
+                server => Some(server.parse().expect("Invalid DNS_SERVER")),
+            },
+            server_addr: env!("SERVER_ADDR").into(),
+            private_key: include_bytes!(env!("PRIVATE_KEY_PATH")).into(),
+            server_public_key: include_bytes!(env!("SERVER_PUBLIC_KEY_PATH")).into(),
+            group_ids: env!("DEVICE_GROUPS")
+                .split(',')
+                .filter(|group| !group.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+
+    fn encode_body(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        push_field(&mut body, self.device_id.as_bytes());
+        push_field(&mut body, self.wifi_ssid.as_bytes());
+        push_field(&mut body, self.wifi_password.as_bytes());
+        push_field(&mut body, &[self.use_dhcp as u8]);
+        match self.ip_cidr {
+            Some(cidr) => {
+                let mut field = cidr.address().octets().to_vec();
+                field.push(cidr.prefix_len());
+                push_field(&mut body, &field);
+            }
+            None => push_field(&mut body, &[]),
+        }
+        match self.dns_server {
+            Some(server) => push_field(&mut body, &server.octets()),
+            None => push_field(&mut body, &[]),
+        }
+        push_field(&mut body, self.server_addr.as_bytes());
+        push_field(&mut body, &self.private_key);
+        push_field(&mut body, &self.server_public_key);
+        push_field(&mut body, self.group_ids.join(",").as_bytes());
+        body
+    }
+
+    /// `build_hash` is the checksum of the build's own encoded values, so a record saved
+    /// by a build with different values can be told apart.
+    fn encode(&self, build_hash: u32) -> Result<Vec<u8>> {
+        let body = self.encode_body();
+
+        let mut record = Vec::with_capacity(RUNTIME_CONFIG_HEADER_LEN + body.len() + 4);
+        record.extend_from_slice(&RUNTIME_CONFIG_MAGIC);
+        record.push(RUNTIME_CONFIG_VERSION);
+        record.extend_from_slice(&build_hash.to_le_bytes());
+        record.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        record.extend_from_slice(&body);
+        record.extend_from_slice(&checksum(&record).to_le_bytes());
+
+        if record.len() > RUNTIME_CONFIG_MAX_LEN {
+            bail!(
+                "Runtime config is {} bytes, more than the {} reserved for it",
+                record.len(),
+                RUNTIME_CONFIG_MAX_LEN
+            );
+        }
+
+        Ok(record)
+    }
+
+    /// Returns `None` for a missing or corrupt record, or one saved by a build with
+    /// different values.
+    fn decode(record: &[u8], build_hash: u32) -> Result<Option<Self>> {
+        let (header, rest) = record.split_at(RUNTIME_CONFIG_HEADER_LEN);
+        if header[0..4] != RUNTIME_CONFIG_MAGIC
+            || header[4] != RUNTIME_CONFIG_VERSION
+            || header[5..9] != build_hash.to_le_bytes()
+        {
+            return Ok(None);
+        }
+
+        let body_len = u16::from_le_bytes([header[9], header[10]]) as usize;
+        if body_len + 4 > rest.len() {
+            return Ok(None);
+        }
+        let (body, rest) = rest.split_at(body_len);
+        let signed_len = RUNTIME_CONFIG_HEADER_LEN + body_len;
+        if rest[..4] != checksum(&record[..signed_len]).to_le_bytes() {
+            return Ok(None);
+        }
+
+        let mut fields = FieldReader { body };
+        Ok(Some(Self {
+            device_id: fields.string()?,
+            wifi_ssid: fields.string()?,
+            wifi_password: fields.string()?,
+            use_dhcp: fields.next()? == [1],
+            ip_cidr: match fields.next()? {
+                [a, b, c, d, prefix] => Some(Ipv4Cidr::new(Ipv4Addr::new(*a, *b, *c, *d), *prefix)),
+                _ => None,
+            },
+            dns_server: match fields.next()? {
+                [a, b, c, d] => Some(Ipv4Addr::new(*a, *b, *c, *d)),
+                _ => None,
+            },
+            server_addr: fields.string()?,
+            private_key: fields.next()?.into(),
+            server_public_key: fields.next()?.into(),
+            group_ids: fields
+                .string()?
+                .split(',')
+                .filter(|group| !group.is_empty())
+                .map(String::from)
+                .collect(),
+        }))
+    }
+
+    fn load(
+        flash: &mut FlashStorage<'static>,
+        offset: u32,
+        build_hash: u32,
+    ) -> Result<Option<Self>> {
+        let mut record = vec![0; RUNTIME_CONFIG_MAX_LEN];
+        flash
+            .read(offset, &mut record)
+            .map_err(|err| anyhow!("{:?}", err))?;
+
+        Self::decode(&record, build_hash)
+    }
+
+    fn save(&self, flash: &mut FlashStorage<'static>, offset: u32, build_hash: u32) -> Result<()> {
+        flash
+            .write(offset, &self.encode(build_hash)?)
+            .map_err(|err| anyhow!("{:?}", err))
+    }
+}
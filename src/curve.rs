@@ -0,0 +1,80 @@
+use crate::config::str_eq;
+
+/// Output level for every logical brightness (0-100), as a fraction of full duty scaled
+/// so that `u16::MAX` is fully on.
+pub static CURVE: [u16; 101] = build_curve();
+
+const CURVE_NAME: &str = env!("DIMMING_CURVE");
+
+/// Fractional bits of a brightness level, which lets fades move smoothly between the
+/// whole brightness steps of the curve.
+pub const FRACTION_BITS: u32 = 8;
+
+/// Linearly interpolates the curve at a brightness level with `FRACTION_BITS` fractional bits.
+pub fn interpolate(level: u32) -> u32 {
+    let index = (level >> FRACTION_BITS).min(100) as usize;
+    let fraction = level & ((1 << FRACTION_BITS) - 1);
+
+    let low = CURVE[index] as u32;
+    let high = CURVE[(index + 1).min(100)] as u32;
+
+    low + (((high - low) * fraction) >> FRACTION_BITS)
+}
+
+const fn build_curve() -> [u16; 101] {
+    let mut curve = [0; 101];
+
+    let mut i = 0;
+    while i < curve.len() {
+        let brightness = i as f64 / 100.0;
+        curve[i] = (output_level(brightness) * u16::MAX as f64 + 0.5) as u16;
+        i += 1;
+    }
+
+    curve
+}
+
+const fn output_level(brightness: f64) -> f64 {
+    if str_eq(CURVE_NAME, "linear") {
+        brightness
+    } else if str_eq(CURVE_NAME, "gamma") {
+        gamma(brightness)
+    } else if str_eq(CURVE_NAME, "cie") {
+        cie_lightness(brightness)
+    } else {
+        panic!("DIMMING_CURVE must be one of linear, gamma or cie")
+    }
+}
+
+/// Gamma 2.2, computed as x^2 * x^(1/5) since powf isn't usable in const fns.
+const fn gamma(brightness: f64) -> f64 {
+    brightness * brightness * fifth_root(brightness)
+}
+
+const fn fifth_root(x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    // Newton's method, starting above the root so it converges monotonically for x <= 1
+    let mut y = 1.0;
+    let mut i = 0;
+    while i < 32 {
+        let y4 = y * y * y * y;
+        y = (4.0 * y + x / y4) / 5.0;
+        i += 1;
+    }
+
+    y
+}
+
+/// Inverse of the CIE 1931 lightness function, treating brightness as perceived lightness.
+const fn cie_lightness(brightness: f64) -> f64 {
+    let lightness = brightness * 100.0;
+    if lightness <= 8.0 {
+        lightness / 903.3
+    } else {
+        let t = (lightness + 16.0) / 116.0;
+        t * t * t
+    }
+}
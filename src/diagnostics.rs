@@ -0,0 +1,32 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::info;
+use embassy_time::{Duration, Instant, Ticker};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+static SERVER_CONNECTIONS: AtomicU32 = AtomicU32::new(0);
+
+/// Counts a connection to the server, every one after the first is a reconnect.
+pub fn server_connected() {
+    SERVER_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Periodically logs heap usage, uptime and reconnects, so slow leaks and flaky links
+/// show up in long-running devices.
+#[embassy_executor::task]
+pub async fn diagnostics_task() {
+    let mut ticker = Ticker::every(REPORT_INTERVAL);
+
+    loop {
+        ticker.next().await;
+
+        info!(
+            "Uptime {}s, heap {}B used, {}B free, {} server reconnects",
+            Instant::now().as_secs(),
+            esp_alloc::HEAP.used(),
+            esp_alloc::HEAP.free(),
+            SERVER_CONNECTIONS.load(Ordering::Relaxed).saturating_sub(1)
+        );
+    }
+}
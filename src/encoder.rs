@@ -0,0 +1,41 @@
+use embassy_futures::select::select;
+use esp_hal::gpio::Input;
+
+use crate::light::{self, LocalCommand};
+
+/// Change in the encoder position for each (previous, current) pair of pin states, with
+/// the states packed as `a << 1 | b`. Invalid transitions, where a state was skipped, count
+/// for nothing.
+const TRANSITIONS: [i32; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// Most encoders go through a full quadrature cycle between detents.
+const STEPS_PER_DETENT: i32 = 4;
+const BRIGHTNESS_PER_DETENT: i32 = 2;
+
+fn read_state(a: &Input<'static>, b: &Input<'static>) -> usize {
+    ((a.is_high() as usize) << 1) | b.is_high() as usize
+}
+
+/// Decodes a quadrature rotary encoder, adjusting the brightness on each detent. Swap the
+/// A and B pins to reverse the direction.
+#[embassy_executor::task]
+pub async fn encoder_task(mut a: Input<'static>, mut b: Input<'static>) {
+    let mut state = read_state(&a, &b);
+    let mut steps = 0;
+
+    loop {
+        select(a.wait_for_any_edge(), b.wait_for_any_edge()).await;
+
+        let new_state = read_state(&a, &b);
+        steps += TRANSITIONS[(state << 2) | new_state];
+        state = new_state;
+
+        let detents = steps / STEPS_PER_DETENT;
+        if detents != 0 {
+            steps -= detents * STEPS_PER_DETENT;
+            light::send_local(LocalCommand::AdjustBrightness(
+                detents * BRIGHTNESS_PER_DETENT,
+            ));
+        }
+    }
+}
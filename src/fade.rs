@@ -0,0 +1,227 @@
+use core::future::pending;
+
+use alloc::string::ToString;
+use defmt::{debug, warn};
+use embassy_futures::select::{Either, Either4, select, select4};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use esp_hal::gpio::Output;
+
+use crate::{
+    config::parse_u32,
+    curve::FRACTION_BITS,
+    journal::{self, Event},
+    log_error,
+    output::{self, LightOutput},
+    relay::{self, StandbyRelay},
+    watchguard,
+};
+
+const FADE_DURATION: Duration = Duration::from_millis(parse_u32(env!("FADE_DURATION_MS")) as u64);
+
+const STEP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Target brightness and how long to take getting there.
+static TARGET: Signal<CriticalSectionRawMutex, (u32, Duration)> = Signal::new();
+
+/// Starts fading the light towards `brightness`, replacing any fade still in progress.
+pub fn fade_to(brightness: u32) {
+    fade_over(brightness, FADE_DURATION);
+}
+
+/// Like `fade_to`, but taking `duration` instead of the configured fade duration.
+pub fn fade_over(brightness: u32, duration: Duration) {
+    TARGET.signal((brightness, duration));
+
+    #[cfg(feature = "uart-bridge")]
+    crate::bridge::forward(brightness);
+}
+
+struct Fade {
+    from: u32,
+    to: u32,
+    start: Instant,
+    duration: Duration,
+    /// Whether the LEDC peripheral is stepping the duty, otherwise it is done in software.
+    hardware: bool,
+}
+
+impl Fade {
+    /// Returns the brightness level (with `curve::FRACTION_BITS` fractional bits) at this
+    /// point of the fade, or `None` once it has finished.
+    fn level(&self) -> Option<u32> {
+        let elapsed = self.start.elapsed();
+        if elapsed >= self.duration {
+            return None;
+        }
+
+        let from = (self.from << FRACTION_BITS) as i64;
+        let delta = ((self.to << FRACTION_BITS) as i64) - from;
+        let progress = delta * elapsed.as_millis() as i64 / self.duration.as_millis() as i64;
+
+        Some((from + progress) as u32)
+    }
+}
+
+/// Checks the output registers against the commanded state, rewriting them if they have
+/// drifted. Returns whether the output is (again) correct.
+fn audit_output(light_output: &mut LightOutput) -> bool {
+    let Err(err) = light_output.verify() else {
+        return true;
+    };
+    log_error(&err.context("LEDC output drifted from the commanded state"));
+
+    let corrected = light_output
+        .reinitialize()
+        .and_then(|()| light_output.verify());
+    match corrected {
+        Ok(()) => {
+            warn!("LEDC output corrected");
+            journal::record(Event::OutputCorrected);
+            true
+        }
+        Err(err) => {
+            log_error(&err.context("Failed to recover LEDC output"));
+            journal::record(Event::OutputRecoveryFailed);
+            false
+        }
+    }
+}
+
+/// Reprograms the output from scratch after a write to it failed, so a wedged timer or
+/// channel doesn't leave the light stuck at its old level.
+fn recover_output(light_output: &mut LightOutput) {
+    match light_output.reinitialize() {
+        Ok(()) => {
+            warn!("LEDC output recovered");
+            journal::record(Event::OutputCorrected);
+        }
+        Err(err) => {
+            log_error(&err.context("Failed to recover LEDC output"));
+            journal::record(Event::OutputRecoveryFailed);
+        }
+    }
+}
+
+/// Owns the light output, fading towards the latest target brightness so changes are
+/// smooth, while also running the watchguard checks, supply compensation and colour
+/// changes.
+///
+/// Fades are handed to the LEDC hardware where possible, falling back to stepping the
+/// duty in software when the fade can't be expressed in the fade registers.
+///
+/// If a standby relay is wired it is opened once the light has been off for a while and
+/// closed again before the next fade up.
+#[embassy_executor::task]
+pub async fn fade_task(mut light_output: LightOutput, standby_relay: Option<Output<'static>>) {
+    let mut check_ticker = Ticker::every(watchguard::CHECK_INTERVAL);
+    let mut step_ticker = Ticker::every(STEP_INTERVAL);
+    let mut fade: Option<Fade> = None;
+
+    let mut standby_relay = standby_relay.map(StandbyRelay::new);
+    // A light that boots off starts the standby countdown straight away
+    let mut off_since = (light_output.brightness() == 0).then(Instant::now);
+
+    loop {
+        let step = async {
+            match &fade {
+                // Hardware fades only need settling once they have finished
+                Some(fade) if fade.hardware => Timer::at(fade.start + fade.duration).await,
+                Some(_) => step_ticker.next().await,
+                None => pending().await,
+            }
+        };
+
+        let standby = async {
+            match (&standby_relay, off_since) {
+                (Some(relay), Some(off_since)) if relay.is_engaged() => {
+                    Timer::at(off_since + relay::OFF_DELAY).await
+                }
+                _ => pending().await,
+            }
+        };
+
+        let event = select4(
+            TARGET.wait(),
+            select(step, standby),
+            check_ticker.next(),
+            select(output::COMPENSATION.wait(), output::COLOR.wait()),
+        )
+        .await;
+
+        match event {
+            Either4::First((target, duration)) => {
+                if target > 0 {
+                    off_since = None;
+
+                    if let Some(relay) = &mut standby_relay {
+                        relay.engage().await;
+                    }
+                }
+
+                let from = light_output.current_brightness();
+
+                let hardware = match light_output.start_fade(target, duration) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        debug!(
+                            "Falling back to software fade: {}",
+                            err.to_string().as_str()
+                        );
+                        false
+                    }
+                };
+
+                fade = Some(Fade {
+                    from,
+                    to: target,
+                    start: Instant::now(),
+                    duration,
+                    hardware,
+                });
+                step_ticker.reset();
+            }
+            Either4::Second(Either::First(_)) => {
+                let level = match fade.as_ref().and_then(Fade::level) {
+                    Some(level) => level,
+                    None => {
+                        let brightness = fade
+                            .take()
+                            .map_or(light_output.brightness(), |fade| fade.to);
+                        if brightness == 0 {
+                            off_since = Some(Instant::now());
+                        }
+                        brightness << FRACTION_BITS
+                    }
+                };
+
+                // The level is kept even when applying it fails, so reinitializing
+                // applies it again
+                if let Err(err) = light_output.set_level(level) {
+                    log_error(&err.context("Failed to set light brightness"));
+                    recover_output(&mut light_output);
+                }
+            }
+            Either4::Second(Either::Second(_)) => {
+                if let Some(relay) = &mut standby_relay {
+                    relay.disengage();
+                }
+            }
+            Either4::Third(_) => {
+                watchguard::report_check(audit_output(&mut light_output));
+            }
+            Either4::Fourth(Either::First(compensation)) => {
+                if let Err(err) = light_output.set_compensation(compensation) {
+                    log_error(&err.context("Failed to apply supply compensation"));
+                    recover_output(&mut light_output);
+                }
+            }
+            Either4::Fourth(Either::Second(color)) => {
+                if let Err(err) = light_output.set_color(color) {
+                    log_error(&err.context("Failed to set light colour"));
+                    recover_output(&mut light_output);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use crate::{
+    config::parse_u32,
+    light::{self, LocalCommand},
+};
+
+/// Brightness a double click sets the light to.
+const DOUBLE_CLICK_BRIGHTNESS: u32 = {
+    let brightness = parse_u32(env!("BUTTON_DOUBLE_CLICK_BRIGHTNESS"));
+    assert!(
+        brightness <= 100,
+        "BUTTON_DOUBLE_CLICK_BRIGHTNESS must be at most 100"
+    );
+    brightness
+};
+
+/// Brightness a triple click sets the light to, meant as a nightlight level.
+const TRIPLE_CLICK_BRIGHTNESS: u32 = {
+    let brightness = parse_u32(env!("BUTTON_TRIPLE_CLICK_BRIGHTNESS"));
+    assert!(
+        brightness <= 100,
+        "BUTTON_TRIPLE_CLICK_BRIGHTNESS must be at most 100"
+    );
+    brightness
+};
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Gesture {
+    /// One or more presses in quick succession.
+    Clicks(u32),
+    HoldStart,
+    HoldEnd,
+    /// The button has been held for the factory reset time.
+    LongHold,
+}
+
+/// Hands the action bound to a gesture to the light task.
+pub fn dispatch(gesture: Gesture) {
+    let command = match gesture {
+        Gesture::Clicks(1) => LocalCommand::Toggle,
+        Gesture::Clicks(2) => LocalCommand::SetBrightness(DOUBLE_CLICK_BRIGHTNESS),
+        Gesture::Clicks(_) => LocalCommand::SetBrightness(TRIPLE_CLICK_BRIGHTNESS),
+        Gesture::HoldStart => LocalCommand::StartRamp,
+        Gesture::HoldEnd => LocalCommand::StopRamp,
+        Gesture::LongHold => LocalCommand::FactoryReset,
+    };
+
+    light::send_local(command);
+}
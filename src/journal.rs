@@ -0,0 +1,197 @@
+use anyhow::{Result, anyhow, bail};
+use defmt::{info, warn};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Instant;
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+
+use crate::{partition::Partition, storage::checksum};
+
+const ENTRY_MARKER: u8 = 0xa5;
+const ENTRY_LEN: usize = 16;
+const ENTRY_COUNT: usize = 32;
+
+const PARTITION: Partition = Partition {
+    label: "devctl_log",
+    subtype: 0x42,
+    len: ENTRY_LEN * ENTRY_COUNT,
+};
+
+static EVENTS: Channel<CriticalSectionRawMutex, Event, 4> = Channel::new();
+
+/// Critical events worth keeping across reboots.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Event {
+    /// The firmware started, with the raw SoC reset reason.
+    Boot {
+        reset_reason: u8,
+    },
+    OutputCheckFailed,
+    /// The light task stopped reporting output checks.
+    OutputStalled,
+    OutputRecoveryFailed,
+    /// The output registers drifted or couldn't be written, and were reprogrammed.
+    OutputCorrected,
+    #[cfg_attr(not(feature = "supply-sense"), allow(dead_code))]
+    SupplyUndervoltage,
+}
+
+impl Event {
+    fn encode(self) -> (u8, u8) {
+        match self {
+            Event::Boot { reset_reason } => (1, reset_reason),
+            Event::OutputCheckFailed => (2, 0),
+            Event::OutputStalled => (3, 0),
+            Event::OutputRecoveryFailed => (4, 0),
+            Event::SupplyUndervoltage => (5, 0),
+            Event::OutputCorrected => (6, 0),
+        }
+    }
+
+    fn decode(kind: u8, detail: u8) -> Option<Self> {
+        Some(match kind {
+            1 => Event::Boot {
+                reset_reason: detail,
+            },
+            2 => Event::OutputCheckFailed,
+            3 => Event::OutputStalled,
+            4 => Event::OutputRecoveryFailed,
+            5 => Event::SupplyUndervoltage,
+            6 => Event::OutputCorrected,
+            _ => return None,
+        })
+    }
+}
+
+/// A journaled event, stamped with the boot it happened in and the uptime at the time so
+/// failures can be matched up with the resets that followed them.
+#[derive(Clone, Copy)]
+struct Entry {
+    boot: u32,
+    uptime_ms: u32,
+    event: Event,
+}
+
+impl Entry {
+    fn encode(&self) -> [u8; ENTRY_LEN] {
+        let (kind, detail) = self.event.encode();
+
+        let mut bytes = [0; ENTRY_LEN];
+        bytes[0] = ENTRY_MARKER;
+        bytes[1] = kind;
+        bytes[2] = detail;
+        bytes[4..8].copy_from_slice(&self.boot.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.uptime_ms.to_le_bytes());
+
+        let checksum = checksum(&bytes[..ENTRY_LEN - 4]);
+        bytes[ENTRY_LEN - 4..].copy_from_slice(&checksum.to_le_bytes());
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (body, stored_checksum) = bytes.split_at(ENTRY_LEN - 4);
+        if body[0] != ENTRY_MARKER || stored_checksum != checksum(body).to_le_bytes() {
+            return None;
+        }
+
+        Some(Self {
+            boot: u32::from_le_bytes(body[4..8].try_into().ok()?),
+            uptime_ms: u32::from_le_bytes(body[8..12].try_into().ok()?),
+            event: Event::decode(body[1], body[2])?,
+        })
+    }
+
+    fn order(&self) -> (u32, u32) {
+        (self.boot, self.uptime_ms)
+    }
+}
+
+/// Ring buffer of the most recent critical events, kept in flash so intermittent failures
+/// still leave evidence after several reboots.
+///
+/// Each kind of event is only journaled once per boot, so a persistent fault can't wear
+/// out the flash or push the history out of the ring.
+#[derive(Default)]
+pub struct Journal {
+    /// Where the journal partition starts, `None` if the journal couldn't be loaded.
+    offset: Option<u32>,
+    boot: u32,
+    next_slot: usize,
+    /// Bit per event kind already journaled this boot.
+    recorded_kinds: u8,
+}
+
+impl Journal {
+    /// Reads the journal, logging its entries oldest first, and starts a new boot in it.
+    pub fn load(flash: &mut FlashStorage<'static>) -> Result<Self> {
+        let offset = PARTITION.find(flash)?;
+        let mut bytes = [0; ENTRY_LEN * ENTRY_COUNT];
+        flash
+            .read(offset, &mut bytes)
+            .map_err(|err| anyhow!("{:?}", err))?;
+
+        let mut entries: heapless::Vec<(usize, Entry), ENTRY_COUNT> = bytes
+            .chunks_exact(ENTRY_LEN)
+            .enumerate()
+            .filter_map(|(slot, bytes)| Some((slot, Entry::decode(bytes)?)))
+            .collect();
+        entries.sort_unstable_by_key(|(_, entry)| entry.order());
+
+        if !entries.is_empty() {
+            info!("Error journal:");
+        }
+        for (_, entry) in &entries {
+            info!(
+                "   boot {} at {}ms: {}",
+                entry.boot, entry.uptime_ms, entry.event
+            );
+        }
+
+        let newest = entries.last();
+        Ok(Self {
+            offset: Some(offset),
+            boot: newest.map_or(0, |(_, entry)| entry.boot.wrapping_add(1)),
+            next_slot: newest.map_or(0, |(slot, _)| (slot + 1) % ENTRY_COUNT),
+            recorded_kinds: 0,
+        })
+    }
+
+    pub fn append(&mut self, flash: &mut FlashStorage<'static>, event: Event) -> Result<()> {
+        let kind_bit = 1 << event.encode().0;
+        if self.recorded_kinds & kind_bit != 0 {
+            return Ok(());
+        }
+        let Some(offset) = self.offset else {
+            bail!("No {} partition to keep the journal in", PARTITION.label);
+        };
+
+        let entry = Entry {
+            boot: self.boot,
+            uptime_ms: Instant::now().as_millis() as u32,
+            event,
+        };
+
+        flash
+            .write(
+                offset + (self.next_slot * ENTRY_LEN) as u32,
+                &entry.encode(),
+            )
+            .map_err(|err| anyhow!("{:?}", err))?;
+        self.next_slot = (self.next_slot + 1) % ENTRY_COUNT;
+        self.recorded_kinds |= kind_bit;
+
+        Ok(())
+    }
+}
+
+/// Queues a critical event to be written to the journal.
+pub fn record(event: Event) {
+    if EVENTS.try_send(event).is_err() {
+        warn!("Dropping journal event {}, queue is full", event);
+    }
+}
+
+pub async fn next_event() -> Event {
+    EVENTS.receive().await
+}
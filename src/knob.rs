@@ -0,0 +1,59 @@
+use embassy_time::{Duration, Ticker};
+use esp_hal::{
+    analog::adc::AdcPin,
+    peripherals::{ADC1, GPIO1},
+};
+
+use crate::{
+    analog::{self, SharedAdc},
+    light::{self, LocalCommand},
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+const ADC_MAX: u32 = 4095;
+/// Readings this close to either end of the track are treated as the end, since most
+/// potentiometers never quite reach their rails.
+const END_MARGIN: u32 = 60;
+
+/// How far (in brightness) the knob has to move before it takes over the light, so ADC
+/// noise doesn't keep overriding the server.
+const DEAD_BAND: u32 = 2;
+
+fn brightness_for(reading: u32) -> u32 {
+    let span = ADC_MAX - 2 * END_MARGIN;
+    reading.saturating_sub(END_MARGIN).min(span) * 100 / span
+}
+
+/// Follows a potentiometer used as a local dimmer. Whichever of the knob and the server
+/// changed the brightness last wins, so the knob only takes over once it is turned.
+#[embassy_executor::task]
+pub async fn knob_task(adc: &'static SharedAdc, mut pin: AdcPin<GPIO1<'static>, ADC1<'static>>) {
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+
+    // The position at boot is taken as is, so the restored brightness isn't overridden
+    let mut filtered = loop {
+        if let Some(raw) = analog::read(adc, &mut pin) {
+            break raw as u32;
+        }
+        ticker.next().await;
+    };
+    let mut applied = brightness_for(filtered);
+
+    loop {
+        ticker.next().await;
+
+        let Some(raw) = analog::read(adc, &mut pin) else {
+            continue;
+        };
+        filtered = (filtered * 3 + raw as u32) / 4;
+
+        let brightness = brightness_for(filtered);
+        // Always follow the knob onto the ends so it can fully turn the light on or off
+        let at_end = brightness != applied && (brightness == 0 || brightness == 100);
+        if brightness.abs_diff(applied) >= DEAD_BAND || at_end {
+            applied = brightness;
+            light::send_local(LocalCommand::SetBrightness(brightness));
+        }
+    }
+}
@@ -1,21 +1,34 @@
-use defmt::{error, info, warn};
+use core::future::pending;
+
+use defmt::{info, warn};
+#[cfg(not(feature = "rgb"))]
+use devicectrl_common::device_types::dimmable_light::DimmableLightState;
+#[cfg(feature = "rgb")]
+use devicectrl_common::device_types::led_strip::{self, LedStripState};
 use devicectrl_common::{
     DeviceId, DeviceState,
-    device_types::{
-        NumericProperties, NumericState, dimmable_light::DimmableLightState, switch::SwitchPower,
-    },
+    device_types::{NumericProperties, NumericState, switch::SwitchPower},
     protocol::simple::{
         DeviceBoundSimpleMessage, ServerBoundSimpleMessage,
         esp::{TransportChannels, TransportEvent},
     },
     updates::AttributeUpdate,
 };
-use esp_hal::ledc::{
-    LowSpeed,
-    channel::{Channel, ChannelIFace},
-};
+use embassy_futures::select::{Either, Either4, select, select4};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 
-use crate::log_error;
+#[cfg(feature = "nightlight")]
+use crate::nightlight;
+#[cfg(feature = "sntp")]
+use crate::sntp;
+use crate::{
+    config::{self, parse_u32},
+    diagnostics, fade, log_error,
+    output::Color,
+    status::{self, Diagnostic},
+    storage::{self, Settings},
+};
 
 const BRIGHTNESS_PROPS: NumericProperties = NumericProperties {
     min: 0,
@@ -23,110 +36,498 @@ const BRIGHTNESS_PROPS: NumericProperties = NumericProperties {
     step: 1,
 };
 
-fn build_state(current_brightness: NumericState) -> DeviceState {
-    DeviceState::DimmableLight(DimmableLightState {
-        power: if current_brightness.value > 0 {
+/// Changes requested by the physical controls on the light.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum LocalCommand {
+    #[cfg_attr(
+        not(any(feature = "knob", feature = "button", feature = "uart-bridge")),
+        allow(dead_code)
+    )]
+    SetBrightness(u32),
+    /// Moves the brightness by a relative amount, stopping at the ends of the range.
+    #[cfg_attr(not(feature = "encoder"), allow(dead_code))]
+    AdjustBrightness(i32),
+    /// Switches between off and the last brightness the light was on at.
+    #[cfg_attr(not(feature = "button"), allow(dead_code))]
+    Toggle,
+    /// Starts ramping the brightness, in the opposite direction to the previous ramp.
+    #[cfg_attr(not(feature = "button"), allow(dead_code))]
+    StartRamp,
+    #[cfg_attr(not(feature = "button"), allow(dead_code))]
+    StopRamp,
+    /// Erases the saved settings and config and reboots.
+    #[cfg_attr(not(feature = "button"), allow(dead_code))]
+    FactoryReset,
+}
+
+/// How long the server can be unreachable before the light goes to `FAILSAFE_BRIGHTNESS`,
+/// `None` when the failsafe is disabled.
+const FAILSAFE_AFTER: Option<Duration> = match parse_u32(env!("FAILSAFE_AFTER_H")) {
+    0 => None,
+    #[cfg(feature = "sntp")]
+    hours => Some(Duration::from_secs(hours as u64 * 60 * 60)),
+    #[cfg(not(feature = "sntp"))]
+    _ => panic!("FAILSAFE_AFTER_H needs the sntp feature to know when it is evening"),
+};
+/// Local hours the failsafe applies between, so it only turns lights on in the evening.
+#[cfg(feature = "sntp")]
+const FAILSAFE_FROM_HOUR: u32 = sntp::hour(env!("FAILSAFE_FROM_H"));
+#[cfg(feature = "sntp")]
+const FAILSAFE_UNTIL_HOUR: u32 = sntp::hour(env!("FAILSAFE_UNTIL_H"));
+/// How often a due failsafe checks whether the evening window has started.
+const FAILSAFE_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+const FAILSAFE_BRIGHTNESS: u32 = {
+    let brightness = parse_u32(env!("FAILSAFE_BRIGHTNESS"));
+    assert!(brightness <= 100, "FAILSAFE_BRIGHTNESS must be at most 100");
+    brightness
+};
+
+/// Shortest time between state notifications, so ramps and fast slider or encoder moves
+/// don't flood the server.
+const NOTIFY_MIN_INTERVAL: Duration = Duration::from_millis(200);
+/// How often the state is sent even when it hasn't changed.
+const STATE_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long a ramp takes to move the brightness by one step, so a full sweep takes 4s.
+const RAMP_STEP_INTERVAL: Duration = Duration::from_millis(40);
+
+static LOCAL_COMMANDS: Channel<CriticalSectionRawMutex, LocalCommand, 4> = Channel::new();
+
+/// Queues a command from a local control, dropping it if the light task is backed up.
+#[cfg_attr(
+    not(any(
+        feature = "knob",
+        feature = "button",
+        feature = "encoder",
+        feature = "uart-bridge"
+    )),
+    allow(dead_code)
+)]
+pub fn send_local(command: LocalCommand) {
+    if LOCAL_COMMANDS.try_send(command).is_err() {
+        warn!("Dropping local command {}, queue is full", command);
+    }
+}
+
+/// Queues a state notification for the server, returning false if the queue is full.
+fn notify_state(transport: &TransportChannels, state: DeviceState) -> bool {
+    let notification =
+        ServerBoundSimpleMessage::UpdateNotification(devicectrl_common::UpdateNotification {
+            device_id: DeviceId::from(config::runtime().device_id.as_str()).unwrap(),
+            reachable: true,
+            new_state: state,
+        });
+
+    transport.outgoing.try_send(notification).is_ok()
+}
+
+/// Whether a query is addressed to every device, by an empty or `*` device id.
+fn is_broadcast(device_id: &str) -> bool {
+    matches!(device_id, "" | "*")
+}
+
+/// Whether a message is addressed to this device, by its own id or one of its groups.
+fn is_addressed_to_us(device_id: &str) -> bool {
+    let config = config::runtime();
+    device_id == config.device_id || config.group_ids.iter().any(|group| group == device_id)
+}
+
+struct Light {
+    current_brightness: NumericState,
+    last_brightness: u32,
+    color: Color,
+    /// Direction of the ramp in progress (or the last one), true when brightening.
+    ramp_up: bool,
+    ramping: bool,
+    /// When the server was last known to be reachable, `None` while it is.
+    server_lost_since: Option<Instant>,
+    /// Whether the failsafe has already been applied during this outage, so it doesn't
+    /// override any local changes made after it.
+    failsafe_applied: bool,
+    /// When a failsafe that came due outside its window checks the time again.
+    failsafe_recheck: Option<Instant>,
+    /// Whether the server is missing a state change, to be sent once it is reachable.
+    notification_pending: bool,
+    /// The brightness and colour the server was last told about.
+    reported: Option<(u32, Color)>,
+    /// When the state was last sent, successfully or not.
+    reported_at: Instant,
+}
+
+impl Light {
+    /// Reported as an LED strip with the rgb feature, otherwise as a dimmable light.
+    fn state(&self) -> DeviceState {
+        let power = if self.current_brightness.value > 0 {
             SwitchPower::On
         } else {
             SwitchPower::Off
-        },
-        brightness: current_brightness,
-    })
-}
+        };
 
-#[embassy_executor::task]
-pub async fn app_task(
-    led_channel: &'static mut Channel<'static, LowSpeed>,
-    transport: &'static TransportChannels,
-) {
-    let mut current_brightness = BRIGHTNESS_PROPS.to_state(0);
-    let mut last_brightness = 0;
+        #[cfg(feature = "rgb")]
+        {
+            let [r, g, b] = self.color;
+            DeviceState::LedStrip(LedStripState {
+                power,
+                brightness: self.current_brightness,
+                color: led_strip::Color { r, g, b },
+            })
+        }
+        #[cfg(not(feature = "rgb"))]
+        {
+            DeviceState::DimmableLight(DimmableLightState {
+                power,
+                brightness: self.current_brightness,
+            })
+        }
+    }
 
-    loop {
-        match transport.incoming.receive().await {
+    fn apply(&mut self, new_brightness: u32) {
+        self.current_brightness.value = new_brightness;
+        if new_brightness > 0 {
+            self.last_brightness = new_brightness;
+        }
+    }
+
+    /// Saves the brightness and tells the server about it.
+    fn commit(&mut self, transport: &TransportChannels) {
+        storage::save(Settings {
+            brightness: self.current_brightness.value,
+            last_brightness: self.last_brightness,
+            color: self.color,
+        });
+
+        self.notify_changed(transport);
+    }
+
+    fn unreported(&self) -> bool {
+        self.notification_pending
+            || self.reported != Some((self.current_brightness.value, self.color))
+    }
+
+    /// Tells the server the current state, unless it already knows it. Changes coming in
+    /// faster than `NOTIFY_MIN_INTERVAL` are coalesced and sent once it has passed.
+    fn notify_changed(&mut self, transport: &TransportChannels) {
+        if self.unreported() && self.reported_at.elapsed() >= NOTIFY_MIN_INTERVAL {
+            self.notify(transport);
+        }
+    }
+
+    /// Tells the server the current state. While it is unreachable only the fact that it
+    /// is out of date is kept, so it gets the latest state on reconnect rather than a
+    /// backlog of stale ones.
+    fn notify(&mut self, transport: &TransportChannels) {
+        if self.server_lost_since.is_some() {
+            self.notification_pending = true;
+            return;
+        }
+
+        self.reported_at = Instant::now();
+        self.notification_pending = !notify_state(transport, self.state());
+        if self.notification_pending {
+            warn!("Outgoing queue is full, deferring state notification");
+        } else {
+            self.reported = Some((self.current_brightness.value, self.color));
+        }
+    }
+
+    /// When to next send the state: once `NOTIFY_MIN_INTERVAL` has passed if it has
+    /// changes held back, otherwise a periodic refresh in case the server missed a
+    /// notification. `None` while the server is unreachable.
+    fn notification_due(&self) -> Option<Instant> {
+        if self.server_lost_since.is_some() {
+            return None;
+        }
+
+        let interval = if self.unreported() {
+            NOTIFY_MIN_INTERVAL
+        } else {
+            STATE_REFRESH_INTERVAL
+        };
+        Some(self.reported_at + interval)
+    }
+
+    /// Fades to a new brightness, saving it and telling the server. Any ramp in progress
+    /// is cancelled, since the latest command wins.
+    fn set_brightness(&mut self, transport: &TransportChannels, new_brightness: u32) {
+        info!("Setting light brightness to [{}]", new_brightness);
+
+        self.ramping = false;
+        fade::fade_to(new_brightness);
+        self.apply(new_brightness);
+
+        self.commit(transport);
+    }
+
+    /// Switches to a new colour at the current brightness, saving it and telling the server.
+    #[cfg(feature = "rgb")]
+    fn set_color(&mut self, transport: &TransportChannels, color: Color) {
+        info!("Setting light colour to {}", color);
+
+        crate::output::COLOR.signal(color);
+        self.color = color;
+
+        self.commit(transport);
+    }
+
+    /// Moves a ramp on by one step. Ramps stop at the lowest brightness instead of turning
+    /// the light off, that is left to a press of the button. The server follows the ramp
+    /// as it goes, but the brightness is only saved once it stops.
+    fn step_ramp(&mut self, transport: &TransportChannels) {
+        let brightness = self.current_brightness.value;
+        let next = if self.ramp_up {
+            (brightness + 1).min(BRIGHTNESS_PROPS.max)
+        } else {
+            brightness.saturating_sub(1).max(1)
+        };
+
+        if next != brightness {
+            fade::fade_over(next, RAMP_STEP_INTERVAL);
+            self.apply(next);
+            self.notify_changed(transport);
+        }
+    }
+
+    fn handle_event(&mut self, transport: &TransportChannels, event: TransportEvent) {
+        // Any message also shows the server is reachable again
+        if matches!(event, TransportEvent::Message(_)) {
+            self.server_reachable();
+        }
+
+        match event {
             TransportEvent::Connected => {
                 info!("Connected to server!");
+                status::report(Diagnostic::Connected);
+                diagnostics::server_connected();
+                self.server_reachable();
 
-                // This isn't required, but its nice to tell the server our initial state
-                transport
-                    .outgoing
-                    .send(ServerBoundSimpleMessage::UpdateNotification(
-                        devicectrl_common::UpdateNotification {
-                            device_id: DeviceId::from(crate::DEVICE_ID).unwrap(),
-                            reachable: true,
-                            new_state: build_state(current_brightness),
-                        },
-                    ))
-                    .await;
+                // Also catches the server up on anything that changed while it was away
+                if self.notification_pending {
+                    info!("Sending state changed while the server was unreachable");
+                }
+                self.notify(transport);
             }
             TransportEvent::Error(err) => {
                 log_error(&err);
+                status::report(Diagnostic::ServerError);
+                self.server_lost_since.get_or_insert_with(Instant::now);
             }
             TransportEvent::Message(DeviceBoundSimpleMessage::UpdateCommand(update)) => {
-                if update.device_id.as_str() != crate::DEVICE_ID {
+                if !is_addressed_to_us(update.device_id.as_str()) {
                     warn!(
                         "Received update command for different device {}!",
                         update.device_id.as_str()
                     );
-                    continue;
+                    return;
                 }
 
                 let new_brightness = match update.update {
-                    AttributeUpdate::Power(SwitchPower::On) => last_brightness,
+                    AttributeUpdate::Power(SwitchPower::On) => self.on_brightness(),
                     AttributeUpdate::Power(SwitchPower::Off) => 0,
                     AttributeUpdate::Brightness(brightness) => {
-                        brightness.apply_to(&current_brightness)
+                        brightness.apply_to(&self.current_brightness)
+                    }
+                    #[cfg(feature = "rgb")]
+                    AttributeUpdate::Color(led_strip::Color { r, g, b }) => {
+                        self.set_color(transport, [r, g, b]);
+                        return;
                     }
 
                     _ => {
-                        warn!("Requested state is not a dimmable light state!");
-                        continue;
+                        warn!("Requested state is not supported by this light!");
+                        return;
                     }
                 };
 
-                info!("Setting light brightness to [{}]", new_brightness);
-
-                if let Err(err) = led_channel.set_duty(new_brightness as u8) {
-                    error!("Failed to set duty cycle: {:?}", err);
-                } else {
-                    current_brightness.value = new_brightness;
-                    if new_brightness > 0 {
-                        last_brightness = new_brightness;
-                    }
-                }
-
-                transport
-                    .outgoing
-                    .send(ServerBoundSimpleMessage::UpdateNotification(
-                        devicectrl_common::UpdateNotification {
-                            device_id: DeviceId::from(crate::DEVICE_ID).unwrap(),
-                            reachable: true,
-                            new_state: build_state(current_brightness),
-                        },
-                    ))
-                    .await;
+                self.set_brightness(transport, new_brightness);
             }
             TransportEvent::Message(DeviceBoundSimpleMessage::StateQuery { device_id }) => {
-                if device_id.as_str() != crate::DEVICE_ID {
+                if !is_broadcast(device_id.as_str()) && !is_addressed_to_us(device_id.as_str()) {
                     warn!(
                         "Received state query for different device {}!",
                         device_id.as_str()
                     );
-                    continue;
+                    return;
                 }
 
-                transport
-                    .outgoing
-                    .send(ServerBoundSimpleMessage::UpdateNotification(
-                        devicectrl_common::UpdateNotification {
-                            device_id,
-                            reachable: true,
-                            new_state: build_state(current_brightness),
-                        },
-                    ))
-                    .await;
+                self.notify(transport);
             }
             _ => {}
         }
     }
+
+    /// Brightness to turn the light back on at. Only a plain "on" is capped by the
+    /// nightlight, explicit brightness changes override it.
+    fn on_brightness(&self) -> u32 {
+        #[cfg(feature = "nightlight")]
+        {
+            nightlight::cap(self.last_brightness)
+        }
+        #[cfg(not(feature = "nightlight"))]
+        {
+            self.last_brightness
+        }
+    }
+
+    fn server_reachable(&mut self) {
+        self.server_lost_since = None;
+        self.failsafe_applied = false;
+        self.failsafe_recheck = None;
+    }
+
+    /// When the failsafe should kick in, if the server is unreachable and it hasn't already.
+    fn failsafe_due(&self) -> Option<Instant> {
+        match (self.server_lost_since, FAILSAFE_AFTER) {
+            (Some(lost_since), Some(after)) if !self.failsafe_applied => {
+                Some(self.failsafe_recheck.unwrap_or(lost_since + after))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the local time is inside the failsafe window, never before the clock is synced.
+    fn in_failsafe_window() -> bool {
+        #[cfg(feature = "sntp")]
+        {
+            sntp::local_hour()
+                .is_some_and(|hour| sntp::in_window(hour, FAILSAFE_FROM_HOUR, FAILSAFE_UNTIL_HOUR))
+        }
+        #[cfg(not(feature = "sntp"))]
+        {
+            false
+        }
+    }
+
+    /// Turns the light on at the failsafe brightness, waiting for the evening window if
+    /// the outage outlasts `FAILSAFE_AFTER` at another time of day.
+    fn apply_failsafe(&mut self, transport: &TransportChannels) {
+        if !Self::in_failsafe_window() {
+            self.failsafe_recheck = Some(Instant::now() + FAILSAFE_RECHECK_INTERVAL);
+            return;
+        }
+
+        warn!(
+            "Server unreachable for too long, applying failsafe brightness [{}]",
+            FAILSAFE_BRIGHTNESS
+        );
+        self.set_brightness(transport, FAILSAFE_BRIGHTNESS);
+        self.failsafe_applied = true;
+    }
+
+    fn handle_local(&mut self, transport: &TransportChannels, command: LocalCommand) {
+        match command {
+            LocalCommand::SetBrightness(brightness) => self.set_brightness(transport, brightness),
+            LocalCommand::AdjustBrightness(delta) => {
+                let brightness = self
+                    .current_brightness
+                    .value
+                    .saturating_add_signed(delta)
+                    .min(BRIGHTNESS_PROPS.max);
+                self.set_brightness(transport, brightness)
+            }
+            LocalCommand::Toggle => {
+                let brightness = if self.current_brightness.value > 0 {
+                    0
+                } else {
+                    self.on_brightness()
+                };
+                self.set_brightness(transport, brightness)
+            }
+            LocalCommand::StartRamp => {
+                let brightness = self.current_brightness.value;
+                self.ramp_up = match brightness {
+                    0 | 1 => true,
+                    brightness if brightness >= BRIGHTNESS_PROPS.max => false,
+                    _ => !self.ramp_up,
+                };
+                self.ramping = true;
+
+                info!(
+                    "Ramping light brightness {} from [{}]",
+                    if self.ramp_up { "up" } else { "down" },
+                    brightness
+                );
+                self.step_ramp(transport);
+            }
+            LocalCommand::StopRamp => {
+                if !self.ramping {
+                    return;
+                }
+                self.ramping = false;
+
+                info!("Ramp stopped at [{}]", self.current_brightness.value);
+                self.commit(transport);
+            }
+            LocalCommand::FactoryReset => {
+                // Going dark acknowledges the reset to whoever is holding the button
+                self.ramping = false;
+                fade::fade_to(0);
+                storage::factory_reset();
+            }
+        }
+    }
+}
+
+/// Applies commands from both the server and the local controls, in the order they arrive.
+#[embassy_executor::task]
+pub async fn app_task(transport: &'static TransportChannels, settings: Settings) {
+    let mut light = Light {
+        current_brightness: BRIGHTNESS_PROPS.to_state(settings.brightness),
+        last_brightness: settings.last_brightness,
+        color: settings.color,
+        ramp_up: false,
+        ramping: false,
+        // Not having connected yet counts as the server being unreachable
+        server_lost_since: Some(Instant::now()),
+        failsafe_applied: false,
+        failsafe_recheck: None,
+        notification_pending: false,
+        reported: None,
+        reported_at: Instant::now(),
+    };
+    let mut ramp_ticker = Ticker::every(RAMP_STEP_INTERVAL);
+
+    loop {
+        let ramp_step = async {
+            if light.ramping {
+                ramp_ticker.next().await
+            } else {
+                pending().await
+            }
+        };
+
+        let failsafe = async {
+            match light.failsafe_due() {
+                Some(due) => Timer::at(due).await,
+                None => pending().await,
+            }
+        };
+
+        let notification = async {
+            match light.notification_due() {
+                Some(due) => Timer::at(due).await,
+                None => pending().await,
+            }
+        };
+
+        let event = select4(
+            transport.incoming.receive(),
+            LOCAL_COMMANDS.receive(),
+            ramp_step,
+            select(failsafe, notification),
+        )
+        .await;
+
+        match event {
+            Either4::First(event) => light.handle_event(transport, event),
+            Either4::Second(command) => {
+                if matches!(command, LocalCommand::StartRamp) {
+                    ramp_ticker.reset();
+                }
+                light.handle_local(transport, command)
+            }
+            Either4::Third(_) => light.step_ramp(transport),
+            Either4::Fourth(Either::First(_)) => light.apply_failsafe(transport),
+            Either4::Fourth(Either::Second(_)) => light.notify(transport),
+        }
+    }
 }
@@ -3,46 +3,98 @@
 
 extern crate alloc;
 
-use core::{net::SocketAddrV4, str::FromStr};
+#[cfg(any(feature = "supply-sense", feature = "knob"))]
+use core::cell::RefCell;
+use core::{
+    future::Future,
+    net::{Ipv4Addr, SocketAddrV4},
+    str::FromStr,
+};
 
 use alloc::string::ToString;
-use anyhow::Error;
-use defmt::{error, println};
+use anyhow::{Error, Result, anyhow, bail};
+use defmt::{error, info, println, warn};
+// The net-log feature brings its own global logger, which also writes to RTT
+#[cfg(not(feature = "net-log"))]
 use defmt_rtt as _;
 use devicectrl_common::protocol::simple::esp::{TransportChannels, transport_task};
 use embassy_executor::Spawner;
-use embassy_net::{Runner, Stack, StackResources, StaticConfigV4};
-use embassy_time::{Duration, Timer};
+use embassy_net::{
+    ConfigV4, IpAddress, Runner, Stack, StackResources, StaticConfigV4, dns::DnsQueryType,
+};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
 use esp_backtrace as _;
+#[cfg(any(feature = "supply-sense", feature = "knob"))]
+use esp_hal::analog::adc::{Adc, AdcConfig, Attenuation};
 use esp_hal::{
     clock::CpuClock,
     ecc::Ecc,
-    gpio::{DriveMode, Level, Output, OutputConfig},
+    gpio::{Level, Output, OutputConfig},
     interrupt::software::SoftwareInterruptControl,
-    ledc::{
-        self, LSGlobalClkSource, Ledc, LowSpeed,
-        channel::{self, Channel, ChannelIFace},
-        timer::{self, LSClockSource, TimerIFace, config::Duty},
-    },
+    ledc::{self, LSGlobalClkSource, Ledc, LowSpeed, channel::Channel, timer::TimerIFace},
     rng::{Rng, Trng},
     sha::Sha,
-    time::Rate,
+    system::Cpu,
     timer::timg::TimerGroup,
 };
 use esp_radio::wifi::WifiDevice;
+use esp_storage::FlashStorage;
 use esp32_ecdsa::CryptoContext;
-use heapless::Vec;
 use p256::{
     PublicKey, SecretKey,
     pkcs8::{DecodePrivateKey, DecodePublicKey},
 };
 
-use crate::{light::app_task, wifi::wifi_connection};
+use crate::{
+    fade::fade_task,
+    journal::{Event, Journal},
+    light::app_task,
+    output::{LightOutput, PwmMode},
+    status::{self, Diagnostic},
+    storage::{POWER_ON_BEHAVIOR, SettingsStore, storage_task},
+    watchguard::watchguard_task,
+    wifi::wifi_connection,
+};
 
+#[cfg(any(feature = "supply-sense", feature = "knob"))]
+mod analog;
+mod board;
+#[cfg(feature = "uart-bridge")]
+mod bridge;
+#[cfg(feature = "button")]
+mod button;
+mod config;
+mod curve;
+mod diagnostics;
+#[cfg(feature = "encoder")]
+mod encoder;
+mod fade;
+#[cfg(feature = "button")]
+mod gesture;
+mod journal;
+#[cfg(feature = "knob")]
+mod knob;
 mod light;
+#[cfg(feature = "mdns")]
+mod mdns;
+#[cfg(feature = "net-log")]
+mod netlog;
+#[cfg(feature = "nightlight")]
+mod nightlight;
+mod output;
+mod partition;
+mod relay;
+#[cfg(feature = "sntp")]
+mod sntp;
+mod status;
+mod storage;
+#[cfg(feature = "supply-sense")]
+mod supply;
+mod watchguard;
 mod wifi;
 
-const DEVICE_ID: &str = env!("DEVICE_ID");
+/// How long to wait for a DHCP lease before using the static `IP_CIDR` instead.
+const DHCP_FALLBACK_TIMEOUT: Duration = Duration::from_secs(30);
 
 macro_rules! mk_static {
     ($t:ty,$val:expr) => {{
@@ -62,25 +114,123 @@ pub fn log_error(err: &Error) {
     })
 }
 
-pub const SERVER_PUBLIC_KEY: &[u8] = include_bytes!(env!("SERVER_PUBLIC_KEY_PATH"));
-pub const PRIVATE_KEY: &[u8] = include_bytes!(env!("PRIVATE_KEY_PATH"));
+/// Waits for a startup stage to complete, logging progress and reporting `failure` every
+/// `timeout` until it does.
+async fn wait_for_stage<F: Future<Output = ()>>(
+    name: &str,
+    timeout: Duration,
+    failure: Diagnostic,
+    mut stage: impl FnMut() -> F,
+) {
+    let start = Instant::now();
+    info!("Waiting for {}...", name);
+
+    while with_timeout(timeout, stage()).await.is_err() {
+        warn!(
+            "{} not ready after {}s, still waiting",
+            name,
+            start.elapsed().as_secs()
+        );
+        status::report(failure);
+    }
+
+    info!("{} ready after {}ms", name, start.elapsed().as_millis());
+}
+
+fn static_ip_config() -> Option<StaticConfigV4> {
+    let runtime = config::runtime();
+
+    Some(StaticConfigV4 {
+        address: runtime.ip_cidr?,
+        gateway: None,
+        dns_servers: runtime.dns_server.into_iter().collect(),
+    })
+}
+
+/// Where the server is, checked from `SERVER_ADDR` before the network starts.
+enum ServerAddr {
+    Ip(SocketAddrV4),
+    Host(&'static str, u16),
+    /// Found through its mDNS advertisement.
+    #[cfg(feature = "mdns")]
+    Discover,
+}
+
+fn parse_server_addr(addr: &'static str) -> Result<ServerAddr> {
+    if addr.is_empty() {
+        #[cfg(feature = "mdns")]
+        return Ok(ServerAddr::Discover);
+        #[cfg(not(feature = "mdns"))]
+        bail!("SERVER_ADDR is empty, which needs the mdns feature to discover the server");
+    }
+
+    let Some((host, port)) = addr.rsplit_once(':') else {
+        bail!("SERVER_ADDR {} must be in the form host:port", addr);
+    };
+    if host.is_empty() {
+        bail!("SERVER_ADDR {} has no host", addr);
+    }
+    let port = port
+        .parse()
+        .map_err(|_| anyhow!("SERVER_ADDR {} has an invalid port", addr))?;
+
+    Ok(match Ipv4Addr::from_str(host) {
+        Ok(ip) => ServerAddr::Ip(SocketAddrV4::new(ip, port)),
+        Err(_) => ServerAddr::Host(host, port),
+    })
+}
+
+/// Resolves a hostname, retrying until it succeeds since the transport can't start
+/// without it.
+async fn resolve_server_addr(stack: &Stack<'_>, host: &str, port: u16) -> SocketAddrV4 {
+    loop {
+        match stack.dns_query(host, DnsQueryType::A).await {
+            Ok(addrs) => {
+                if let Some(IpAddress::Ipv4(ip)) = addrs.first() {
+                    info!("Resolved {} to {}", host, ip.to_string().as_str());
+                    return SocketAddrV4::new(*ip, port);
+                }
+
+                warn!("No addresses found for {}", host);
+            }
+            Err(err) => {
+                log_error(&anyhow!("{:?}", err).context("Failed to resolve server address"))
+            }
+        }
+
+        status::report(Diagnostic::NoDns);
+        Timer::after(Duration::from_secs(5)).await;
+    }
+}
 
 #[esp_rtos::main]
 async fn main(spawner: Spawner) {
+    #[cfg(feature = "net-log")]
+    netlog::init();
+
     let peripherals = esp_hal::init(esp_hal::Config::default().with_cpu_clock(CpuClock::_80MHz));
 
-    esp_alloc::heap_allocator!(size: 72 * 1024);
+    esp_alloc::heap_allocator!(size: config::HEAP_SIZE);
 
     let rng = Rng::new();
 
+    // Everything below reads its per-unit settings from the config in flash
+    let mut settings_store = SettingsStore::new(FlashStorage::new(peripherals.FLASH));
+    config::load_runtime(settings_store.flash());
+    let runtime = config::runtime();
+
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     let sw_int = SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
     esp_rtos::start(timg0.timer0, sw_int.software_interrupt0);
 
     // enable internal antenna
-    Output::new(peripherals.GPIO3, Level::Low, OutputConfig::default());
-    Timer::after(Duration::from_millis(100)).await;
-    Output::new(peripherals.GPIO14, Level::Low, OutputConfig::default());
+    if let Some(gpio) = board::ANTENNA_POWER_GPIO {
+        Output::new(board::pin(gpio), Level::Low, OutputConfig::default());
+        Timer::after(Duration::from_millis(100)).await;
+    }
+    if let Some(gpio) = board::ANTENNA_SELECT_GPIO {
+        Output::new(board::pin(gpio), Level::Low, OutputConfig::default());
+    }
 
     let esp_radio_ctrl = &*mk_static!(
         esp_radio::Controller<'static>,
@@ -91,18 +241,20 @@ async fn main(spawner: Spawner) {
         esp_radio::wifi::new(esp_radio_ctrl, peripherals.WIFI, Default::default())
             .expect("Failed to initialize wifi controller");
 
-    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
-        address: env!("IP_CIDR").parse().unwrap(),
-        gateway: None,
-        dns_servers: Vec::new(),
-    });
+    let config = if runtime.use_dhcp {
+        embassy_net::Config::dhcpv4(Default::default())
+    } else {
+        embassy_net::Config::ipv4_static(
+            static_ip_config().expect("IP_CIDR is required when not using DHCP"),
+        )
+    };
 
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
 
     let (stack, runner) = embassy_net::new(
         interfaces.sta,
         config,
-        mk_static!(StackResources<3>, StackResources::<3>::new()),
+        mk_static!(StackResources<6>, StackResources::<6>::new()),
         seed,
     );
 
@@ -113,48 +265,215 @@ async fn main(spawner: Spawner) {
         sha: Sha::new(peripherals.SHA),
         ecc: Ecc::new(peripherals.ECC),
         trng: Trng::try_new().expect("Failed to initialize TRNG"),
-        secret_key: SecretKey::from_pkcs8_der(PRIVATE_KEY).expect("Failed to decode secret key"),
-        server_public_key: PublicKey::from_public_key_der(SERVER_PUBLIC_KEY)
+        secret_key: SecretKey::from_pkcs8_der(&runtime.private_key)
+            .expect("Failed to decode secret key"),
+        server_public_key: PublicKey::from_public_key_der(&runtime.server_public_key)
             .expect("Failed to decode server public key"),
     };
 
-    let mut ledc = Ledc::new(peripherals.LEDC);
+    let ledc = mk_static!(Ledc<'static>, Ledc::new(peripherals.LEDC));
     ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+    let ledc: &'static Ledc<'static> = ledc;
 
     let lstimer0 = mk_static!(
         ledc::timer::Timer<'_, LowSpeed>,
-        ledc.timer::<LowSpeed>(timer::Number::Timer0)
+        ledc.timer::<LowSpeed>(PwmMode::Normal.timer_number())
     );
     lstimer0
-        .configure(timer::config::Config {
-            duty: Duty::Duty7Bit, // ceil(log2(100))
-            clock_source: LSClockSource::APBClk,
-            frequency: Rate::from_khz(24),
-        })
+        .configure(PwmMode::Normal.timer_config())
         .expect("Failed to configure LEDC timer");
 
-    let led_channel = mk_static!(
-        Channel<'_, LowSpeed>,
-        ledc.channel(channel::Number::Channel0, peripherals.GPIO18)
+    let lstimer1 = mk_static!(
+        ledc::timer::Timer<'_, LowSpeed>,
+        ledc.timer::<LowSpeed>(PwmMode::LowFrequency.timer_number())
+    );
+    lstimer1
+        .configure(PwmMode::LowFrequency.timer_config())
+        .expect("Failed to configure low frequency LEDC timer");
+
+    let led_channels = mk_static!(
+        [Channel<'_, LowSpeed>; output::CHANNEL_COUNT],
+        core::array::from_fn(|i| {
+            ledc.channel(output::CHANNEL_NUMBERS[i], board::pin(board::PWM_GPIOS[i]))
+        })
     );
 
-    led_channel
-        .configure(channel::config::Config {
-            timer: lstimer0,
-            duty_pct: 100,
-            drive_mode: DriveMode::PushPull,
+    // Restore the light before anything network related, so it comes back as it was left
+    let saved_settings = settings_store
+        .load()
+        .unwrap_or_else(|err| {
+            log_error(&err.context("Failed to load settings"));
+            None
         })
+        .unwrap_or_default();
+    let settings = POWER_ON_BEHAVIOR.apply(saved_settings);
+
+    let mut light_output = LightOutput::new(ledc, lstimer0, lstimer1, led_channels, settings.color);
+    light_output
+        .configure(settings.brightness)
         .expect("Failed to configure LEDC channel");
 
+    let mut journal = Journal::load(settings_store.flash()).unwrap_or_else(|err| {
+        log_error(&err.context("Failed to load error journal"));
+        Journal::default()
+    });
+    let reset_reason =
+        esp_hal::rtc_cntl::reset_reason(Cpu::ProCpu).map_or(0, |reason| reason as u8);
+    if let Err(err) = journal.append(settings_store.flash(), Event::Boot { reset_reason }) {
+        log_error(&err.context("Failed to write error journal"));
+    }
+
+    // Active-high enable line of the LED driver, pulled low by the watchguard on failure
+    #[cfg(feature = "kill-switch")]
+    let kill_switch = Some(Output::new(
+        peripherals.GPIO16,
+        Level::High,
+        OutputConfig::default(),
+    ));
+    #[cfg(not(feature = "kill-switch"))]
+    let kill_switch = None;
+
+    #[cfg(feature = "status-led")]
+    let status_led = Output::new(peripherals.GPIO15, Level::High, OutputConfig::default());
+
+    // Disconnects the LED driver while the light has been off for a while
+    #[cfg(feature = "standby-relay")]
+    let standby_relay = Some(Output::new(
+        peripherals.GPIO23,
+        Level::High,
+        OutputConfig::default(),
+    ));
+    #[cfg(not(feature = "standby-relay"))]
+    let standby_relay = None;
+
+    // Both analog inputs sit on ADC1, so their pins are enabled together and it is shared
+    #[cfg(any(feature = "supply-sense", feature = "knob"))]
+    let mut adc_config = AdcConfig::new();
+    #[cfg(feature = "supply-sense")]
+    let supply_pin = adc_config.enable_pin(peripherals.GPIO2, Attenuation::_11dB);
+    #[cfg(feature = "knob")]
+    let knob_pin = adc_config.enable_pin(peripherals.GPIO1, Attenuation::_11dB);
+    #[cfg(any(feature = "supply-sense", feature = "knob"))]
+    let adc = &*mk_static!(
+        analog::SharedAdc,
+        analog::SharedAdc::new(RefCell::new(Adc::new(peripherals.ADC1, adc_config)))
+    );
+
     let transport = mk_static!(TransportChannels, TransportChannels::new());
 
-    let device_id =
-        devicectrl_common::DeviceId::from(DEVICE_ID).expect("Failed to create device id");
+    let device_id = devicectrl_common::DeviceId::from(runtime.device_id.as_str())
+        .expect("Failed to create device id");
+
+    // The light and its local controls run whatever state the network is in
+    spawner
+        .spawn(fade_task(light_output, standby_relay))
+        .unwrap();
+    spawner.spawn(app_task(transport, settings)).unwrap();
+    spawner.spawn(watchguard_task(kill_switch)).unwrap();
+    spawner.spawn(diagnostics::diagnostics_task()).unwrap();
+    spawner
+        .spawn(storage_task(settings_store, saved_settings, journal))
+        .unwrap();
+
+    #[cfg(feature = "button")]
+    spawner
+        .spawn(button::button_task(button::button_input()))
+        .unwrap();
+
+    #[cfg(feature = "supply-sense")]
+    spawner.spawn(supply::supply_task(adc, supply_pin)).unwrap();
+
+    #[cfg(feature = "knob")]
+    spawner.spawn(knob::knob_task(adc, knob_pin)).unwrap();
+
+    #[cfg(feature = "uart-bridge")]
+    {
+        use esp_hal::uart::{Config, Uart};
+
+        let uart = Uart::new(
+            peripherals.UART1,
+            Config::default().with_baudrate(bridge::BAUD_RATE),
+        )
+        .expect("Failed to configure bridge UART")
+        .with_tx(peripherals.GPIO19)
+        .with_rx(peripherals.GPIO20)
+        .into_async();
+        spawner.spawn(bridge::bridge_task(uart)).unwrap();
+    }
 
-    let server_addr = SocketAddrV4::from_str(env!("SERVER_ADDR")).expect("Invalid server address");
+    #[cfg(feature = "encoder")]
+    {
+        use esp_hal::gpio::{Input, InputConfig, Pull};
+
+        let pull_up = || InputConfig::default().with_pull(Pull::Up);
+        spawner
+            .spawn(encoder::encoder_task(
+                Input::new(peripherals.GPIO21, pull_up()),
+                Input::new(peripherals.GPIO22, pull_up()),
+            ))
+            .unwrap();
+    }
+
+    #[cfg(feature = "status-led")]
+    spawner.spawn(status::status_led_task(status_led)).unwrap();
+
+    // A bad address would only show once connected, so the network isn't started with one
+    let server_addr = match parse_server_addr(&runtime.server_addr) {
+        Ok(server_addr) => server_addr,
+        Err(err) => {
+            log_error(&err.context("Invalid server address, staying offline"));
+            status::report(Diagnostic::BadConfig);
+            return;
+        }
+    };
 
     spawner.spawn(wifi_connection(controller)).unwrap();
     spawner.spawn(net_task(runner)).unwrap();
+
+    // Waits for the network itself, so it doesn't hold up on the server lookup
+    #[cfg(feature = "sntp")]
+    spawner.spawn(sntp::sntp_task(*stack)).unwrap();
+    #[cfg(feature = "net-log")]
+    spawner.spawn(netlog::net_log_task(*stack)).unwrap();
+
+    // Bring the stack up in order so the transport never starts connecting without a network
+    let net: &Stack<'_> = stack;
+    wait_for_stage(
+        "wifi link",
+        Duration::from_secs(30),
+        Diagnostic::NoWifi,
+        move || net.wait_link_up(),
+    )
+    .await;
+    if runtime.use_dhcp
+        && let Some(fallback) = static_ip_config()
+        && with_timeout(DHCP_FALLBACK_TIMEOUT, net.wait_config_up())
+            .await
+            .is_err()
+    {
+        warn!("No DHCP lease, falling back to static address");
+        net.set_config_v4(ConfigV4::Static(fallback));
+    }
+
+    wait_for_stage(
+        "ip config",
+        Duration::from_secs(10),
+        Diagnostic::NoIp,
+        move || net.wait_config_up(),
+    )
+    .await;
+
+    if let Some(config) = net.config_v4() {
+        info!("IP address: {}", config.address.to_string().as_str());
+    }
+
+    let server_addr = match server_addr {
+        ServerAddr::Ip(addr) => addr,
+        ServerAddr::Host(host, port) => resolve_server_addr(net, host, port).await,
+        #[cfg(feature = "mdns")]
+        ServerAddr::Discover => mdns::discover_server(*net).await,
+    };
+
     spawner
         .spawn(transport_task(
             stack,
@@ -164,7 +483,9 @@ async fn main(spawner: Spawner) {
             crypto,
         ))
         .unwrap();
-    spawner.spawn(app_task(led_channel, transport)).unwrap();
+
+    #[cfg(feature = "mdns")]
+    spawner.spawn(mdns::mdns_task(*net)).unwrap();
 }
 
 #[embassy_executor::task]
@@ -0,0 +1,414 @@
+use core::{fmt::Write, net::Ipv4Addr, net::SocketAddrV4};
+
+use alloc::string::ToString;
+use anyhow::{Result, anyhow, bail};
+use defmt::{info, warn};
+use embassy_net::{
+    IpAddress, IpEndpoint, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Timer, with_timeout};
+use heapless::{String, Vec};
+
+use crate::{
+    config, log_error,
+    status::{self, Diagnostic},
+};
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// Service type the device advertises itself under.
+const SERVICE: &str = "_devicectrl._tcp.local";
+/// Service type the controller advertises itself under.
+const SERVER_SERVICE: &str = "_devicectrl-server._tcp.local";
+
+const TTL_SECS: u32 = 120;
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+const DISCOVERY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on the class of records only this host answers for.
+const CACHE_FLUSH: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+const PACKET_LEN: usize = 512;
+const NAME_LEN: usize = 128;
+const MAX_LABEL_LEN: usize = 63;
+
+type Packet = Vec<u8, PACKET_LEN>;
+type Name = String<NAME_LEN>;
+
+/// The names the device answers for, built from the device id.
+struct Names {
+    instance: Name,
+    host: Name,
+}
+
+impl Names {
+    fn new(device_id: &str) -> Result<Self> {
+        let mut instance = Name::new();
+        let mut host = Name::new();
+        write!(instance, "{}.{}", device_id, SERVICE)
+            .and_then(|()| write!(host, "{}.local", device_id))
+            .map_err(|_| anyhow!("Device id {} is too long for mDNS", device_id))?;
+
+        for label in instance.split('.').chain(host.split('.')) {
+            if !(1..=MAX_LABEL_LEN).contains(&label.len()) {
+                bail!(
+                    "Device id {} doesn't make valid mDNS names, labels must be 1 to {} bytes",
+                    device_id,
+                    MAX_LABEL_LEN
+                );
+            }
+        }
+
+        Ok(Self { instance, host })
+    }
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        packet.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Reads a (possibly compressed) name, returning it in dotted form along with the offset
+/// just past it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(Name, usize)> {
+    let mut name = Name::new();
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *packet.get(offset)? as usize;
+
+        if len & 0xc0 == 0xc0 {
+            // Bound the number of jumps so a pointer loop can't hang the task
+            jumps += 1;
+            if jumps > 8 {
+                return None;
+            }
+
+            end.get_or_insert(offset + 2);
+            offset = ((len & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+            continue;
+        }
+
+        if len == 0 {
+            return Some((name, end.unwrap_or(offset + 1)));
+        }
+
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        if !name.is_empty() {
+            name.push('.').ok()?;
+        }
+        for &byte in label {
+            name.push(byte as char).ok()?;
+        }
+
+        offset += 1 + len;
+    }
+}
+
+fn write_u16(packet: &mut Packet, value: u16) -> Option<()> {
+    packet.extend_from_slice(&value.to_be_bytes()).ok()
+}
+
+fn write_name(packet: &mut Packet, name: &str) -> Option<()> {
+    for label in name.split('.') {
+        packet.push(label.len() as u8).ok()?;
+        packet.extend_from_slice(label.as_bytes()).ok()?;
+    }
+    packet.push(0).ok()
+}
+
+fn write_header(packet: &mut Packet, flags: u16, questions: u16, answers: u16) -> Option<()> {
+    // Id, flags, then the question, answer, authority and additional counts
+    for field in [0, flags, questions, answers, 0, 0] {
+        write_u16(packet, field)?;
+    }
+    Some(())
+}
+
+fn write_record(
+    packet: &mut Packet,
+    name: &str,
+    record_type: u16,
+    class: u16,
+    rdata: impl FnOnce(&mut Packet) -> Option<()>,
+) -> Option<()> {
+    write_name(packet, name)?;
+    write_u16(packet, record_type)?;
+    write_u16(packet, class)?;
+    packet.extend_from_slice(&TTL_SECS.to_be_bytes()).ok()?;
+
+    // The data length is only known once the data has been written
+    let len_offset = packet.len();
+    write_u16(packet, 0)?;
+    rdata(packet)?;
+    let len = (packet.len() - len_offset - 2) as u16;
+    packet[len_offset..len_offset + 2].copy_from_slice(&len.to_be_bytes());
+
+    Some(())
+}
+
+/// Builds a response holding every record the device answers for.
+fn build_response(names: &Names, address: Ipv4Addr) -> Option<Packet> {
+    let Names { instance, host } = names;
+    let mut packet = Packet::new();
+
+    write_header(&mut packet, FLAG_RESPONSE | FLAG_AUTHORITATIVE, 0, 4)?;
+    write_record(&mut packet, SERVICE, TYPE_PTR, CLASS_IN, |packet| {
+        write_name(packet, instance)
+    })?;
+    write_record(
+        &mut packet,
+        instance,
+        TYPE_SRV,
+        CLASS_IN | CACHE_FLUSH,
+        |packet| {
+            // Priority and weight, then the port. The device only makes outgoing
+            // connections, so the record just serves to locate its host.
+            for field in [0, 0, 0] {
+                write_u16(packet, field)?;
+            }
+            write_name(packet, host)
+        },
+    )?;
+    write_record(
+        &mut packet,
+        instance,
+        TYPE_TXT,
+        CLASS_IN | CACHE_FLUSH,
+        |packet| {
+            let mut entry = Name::new();
+            write!(entry, "id={}", config::runtime().device_id).ok()?;
+            packet.push(entry.len() as u8).ok()?;
+            packet.extend_from_slice(entry.as_bytes()).ok()
+        },
+    )?;
+    write_record(
+        &mut packet,
+        host,
+        TYPE_A,
+        CLASS_IN | CACHE_FLUSH,
+        |packet| packet.extend_from_slice(&address.octets()).ok(),
+    )?;
+
+    Some(packet)
+}
+
+/// Whether a query asks about any of the records the device answers for.
+fn is_relevant_query(names: &Names, packet: &[u8]) -> Option<bool> {
+    if read_u16(packet, 2)? & FLAG_RESPONSE != 0 {
+        return Some(false);
+    }
+
+    let Names { instance, host } = names;
+
+    let mut offset = 12;
+    for _ in 0..read_u16(packet, 4)? {
+        let (name, next) = read_name(packet, offset)?;
+        let question_type = read_u16(packet, next)?;
+        offset = next + 4;
+
+        let relevant = match question_type {
+            TYPE_PTR => name.eq_ignore_ascii_case(SERVICE),
+            TYPE_SRV | TYPE_TXT => name.eq_ignore_ascii_case(instance),
+            TYPE_A => name.eq_ignore_ascii_case(host),
+            TYPE_ANY => [SERVICE, instance.as_str(), host.as_str()]
+                .iter()
+                .any(|ours| name.eq_ignore_ascii_case(ours)),
+            _ => false,
+        };
+        if relevant {
+            return Some(true);
+        }
+    }
+
+    Some(false)
+}
+
+/// Answers mDNS queries for the device, announcing it once at startup.
+#[embassy_executor::task]
+pub async fn mdns_task(stack: Stack<'static>) {
+    let names = match Names::new(&config::runtime().device_id) {
+        Ok(names) => names,
+        Err(err) => {
+            log_error(&err.context("Not advertising over mDNS"));
+            return;
+        }
+    };
+
+    if let Err(err) = stack.join_multicast_group(MDNS_ADDR) {
+        log_error(&anyhow!("{:?}", err).context("Failed to join mDNS group"));
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    if let Err(err) = socket.bind(MDNS_PORT) {
+        log_error(&anyhow!("{:?}", err).context("Failed to bind mDNS socket"));
+        return;
+    }
+
+    let group = IpEndpoint::new(IpAddress::Ipv4(MDNS_ADDR), MDNS_PORT);
+    let mut buf = [0; PACKET_LEN];
+    let mut announce = true;
+
+    loop {
+        if !announce {
+            let len = match socket.recv_from(&mut buf).await {
+                Ok((len, _)) => len,
+                Err(err) => {
+                    log_error(&anyhow!("{:?}", err).context("Failed to receive mDNS packet"));
+                    continue;
+                }
+            };
+
+            if is_relevant_query(&names, &buf[..len]) != Some(true) {
+                continue;
+            }
+        }
+        announce = false;
+
+        let Some(config) = stack.config_v4() else {
+            continue;
+        };
+        let Some(response) = build_response(&names, config.address.address()) else {
+            warn!("mDNS response doesn't fit in a packet");
+            continue;
+        };
+
+        if let Err(err) = socket.send_to(&response, group).await {
+            log_error(&anyhow!("{:?}", err).context("Failed to send mDNS response"));
+        }
+    }
+}
+
+/// Picks the controller's address out of a response to the discovery query.
+fn parse_server_addr(packet: &[u8]) -> Option<SocketAddrV4> {
+    if read_u16(packet, 2)? & FLAG_RESPONSE == 0 {
+        return None;
+    }
+
+    let mut offset = 12;
+    for _ in 0..read_u16(packet, 4)? {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+
+    // Answers, authority and additional records are all searched, responders tend to put
+    // the SRV and A records in the additional section
+    let records = read_u16(packet, 6)? + read_u16(packet, 8)? + read_u16(packet, 10)?;
+    let mut target: Option<(Name, u16)> = None;
+    let mut addresses: Vec<(Name, Ipv4Addr), 4> = Vec::new();
+
+    for _ in 0..records {
+        let (name, next) = read_name(packet, offset)?;
+        let record_type = read_u16(packet, next)?;
+        let data_len = read_u16(packet, next + 8)? as usize;
+        let data = next + 10;
+        offset = data + data_len;
+
+        match record_type {
+            TYPE_SRV if target.is_none() => {
+                let port = read_u16(packet, data + 4)?;
+                target = Some((read_name(packet, data + 6)?.0, port));
+            }
+            TYPE_A if data_len == 4 => {
+                let octets: [u8; 4] = packet.get(data..data + 4)?.try_into().ok()?;
+                let _ = addresses.push((name, Ipv4Addr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+
+    let (host, port) = target?;
+    addresses
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(&host))
+        .map(|(_, ip)| SocketAddrV4::new(*ip, port))
+}
+
+fn build_discovery_query() -> Option<Packet> {
+    let mut packet = Packet::new();
+    write_header(&mut packet, 0, 1, 0)?;
+    write_name(&mut packet, SERVER_SERVICE)?;
+    write_u16(&mut packet, TYPE_PTR)?;
+    write_u16(&mut packet, CLASS_IN)?;
+    Some(packet)
+}
+
+/// Looks up the controller over mDNS, retrying until it answers since the transport
+/// can't start without it.
+pub async fn discover_server(stack: Stack<'static>) -> SocketAddrV4 {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    // Querying from a port other than 5353 gets the answers sent straight back to us
+    socket
+        .bind(0)
+        .expect("Failed to bind mDNS discovery socket");
+
+    let query = build_discovery_query().expect("mDNS discovery query doesn't fit in a packet");
+    let group = IpEndpoint::new(IpAddress::Ipv4(MDNS_ADDR), MDNS_PORT);
+    let mut buf = [0; PACKET_LEN];
+
+    info!("Discovering server over mDNS...");
+
+    loop {
+        if let Err(err) = socket.send_to(&query, group).await {
+            log_error(&anyhow!("{:?}", err).context("Failed to send mDNS query"));
+        }
+
+        let answer = with_timeout(DISCOVERY_TIMEOUT, async {
+            loop {
+                if let Ok((len, _)) = socket.recv_from(&mut buf).await
+                    && let Some(addr) = parse_server_addr(&buf[..len])
+                {
+                    return addr;
+                }
+            }
+        })
+        .await;
+
+        if let Ok(addr) = answer {
+            info!(
+                "Discovered server at {}:{}",
+                addr.ip().to_string().as_str(),
+                addr.port()
+            );
+            return addr;
+        }
+
+        warn!("No server answered the mDNS query");
+        status::report(Diagnostic::NoDns);
+        Timer::after(DISCOVERY_RETRY_DELAY).await;
+    }
+}
@@ -0,0 +1,234 @@
+use core::{
+    cell::RefCell,
+    net::SocketAddrV4,
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use anyhow::anyhow;
+use critical_section::{Mutex, RestoreState};
+use defmt::warn;
+use embassy_net::{
+    IpAddress, IpEndpoint, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Ticker};
+use heapless::{Deque, Vec};
+use rtt_target::{ChannelMode, UpChannel, rtt_init};
+
+use crate::log_error;
+
+const COLLECTOR_ADDR: &str = env!("LOG_COLLECTOR_ADDR");
+
+/// Frames waiting to be sent to the collector. Once it is full new frames are dropped
+/// whole, so logging never waits on the network.
+const BUFFER_LEN: usize = 4096;
+/// Frames longer than this only go to RTT.
+const MAX_FRAME_LEN: usize = 256;
+/// Datagrams only carry whole frames, so losing one doesn't garble the frames after it.
+const DATAGRAM_LEN: usize = 1024;
+const SEND_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Everything the logger writes to while a frame is being logged.
+struct State {
+    encoder: defmt::Encoder,
+    /// `None` until `init`, anything logged before it only goes to the network.
+    rtt: Option<UpChannel>,
+    frame: Vec<u8, MAX_FRAME_LEN>,
+    /// Whether the frame outgrew `MAX_FRAME_LEN`, so it can't be sent.
+    frame_truncated: bool,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    encoder: defmt::Encoder::new(),
+    rtt: None,
+    frame: Vec::new(),
+    frame_truncated: false,
+}));
+static BUFFER: Mutex<RefCell<Deque<u8, BUFFER_LEN>>> = Mutex::new(RefCell::new(Deque::new()));
+/// Frames dropped since the last send, reported once the collector is reachable again.
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut CS_RESTORE: RestoreState = RestoreState::invalid();
+
+/// Sets up the RTT channel the log frames are also written to, so probe-rs still shows
+/// them when attached.
+pub fn init() {
+    let channels = rtt_init! {
+        up: {
+            0: {
+                size: 1024,
+                mode: ChannelMode::NoBlockSkip,
+                name: "defmt"
+            }
+        }
+    };
+
+    critical_section::with(|cs| STATE.borrow_ref_mut(cs).rtt = Some(channels.up.0));
+}
+
+/// Replaces defmt-rtt as the global logger, writing each frame to RTT and keeping a copy
+/// for `net_log_task` to send.
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let restore = unsafe { critical_section::acquire() };
+        if TAKEN.swap(true, Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        // SAFETY: only written while holding the critical section taken above
+        unsafe { CS_RESTORE = restore };
+
+        with_state(|state| {
+            let State {
+                encoder,
+                rtt,
+                frame,
+                frame_truncated,
+            } = state;
+            encoder.start_frame(|bytes| output(rtt, frame, frame_truncated, bytes));
+        });
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        with_state(|state| {
+            let State {
+                encoder,
+                rtt,
+                frame,
+                frame_truncated,
+            } = state;
+            encoder.end_frame(|bytes| output(rtt, frame, frame_truncated, bytes));
+
+            queue_frame(frame, *frame_truncated);
+            frame.clear();
+            *frame_truncated = false;
+        });
+
+        TAKEN.store(false, Ordering::Relaxed);
+        // SAFETY: the restore state is the one `acquire` took the critical section with
+        unsafe { critical_section::release(CS_RESTORE) };
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        with_state(|state| {
+            let State {
+                encoder,
+                rtt,
+                frame,
+                frame_truncated,
+            } = state;
+            encoder.write(bytes, |bytes| output(rtt, frame, frame_truncated, bytes));
+        });
+    }
+}
+
+fn with_state(f: impl FnOnce(&mut State)) {
+    critical_section::with(|cs| f(&mut STATE.borrow_ref_mut(cs)));
+}
+
+fn output(
+    rtt: &mut Option<UpChannel>,
+    frame: &mut Vec<u8, MAX_FRAME_LEN>,
+    frame_truncated: &mut bool,
+    bytes: &[u8],
+) {
+    if let Some(rtt) = rtt {
+        rtt.write(bytes);
+    }
+    if frame.extend_from_slice(bytes).is_err() {
+        *frame_truncated = true;
+    }
+}
+
+fn queue_frame(frame: &[u8], truncated: bool) {
+    critical_section::with(|cs| {
+        let mut buffer = BUFFER.borrow_ref_mut(cs);
+        if truncated || buffer.capacity() - buffer.len() < frame.len() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        for byte in frame {
+            // Can't fail, the space was checked above
+            let _ = buffer.push_back(*byte);
+        }
+    });
+}
+
+/// Takes as many whole frames as fit in a datagram, `None` when none are waiting.
+fn take_datagram() -> Option<Vec<u8, DATAGRAM_LEN>> {
+    critical_section::with(|cs| {
+        let mut buffer = BUFFER.borrow_ref_mut(cs);
+
+        // rzCOBS frames end with the only zero byte in them
+        let len = buffer
+            .iter()
+            .take(DATAGRAM_LEN)
+            .enumerate()
+            .filter(|(_, byte)| **byte == 0)
+            .map(|(i, _)| i + 1)
+            .last()?;
+
+        Some((0..len).filter_map(|_| buffer.pop_front()).collect())
+    })
+}
+
+/// Sends the buffered log frames to `LOG_COLLECTOR_ADDR` over UDP, where `defmt-print`
+/// can decode them with the firmware's ELF.
+#[embassy_executor::task]
+pub async fn net_log_task(stack: Stack<'static>) {
+    let collector = match SocketAddrV4::from_str(COLLECTOR_ADDR) {
+        Ok(addr) => IpEndpoint::new(IpAddress::Ipv4(*addr.ip()), addr.port()),
+        Err(err) => {
+            log_error(
+                &anyhow!("{:?}", err).context("Invalid LOG_COLLECTOR_ADDR, not sending logs"),
+            );
+            return;
+        }
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; DATAGRAM_LEN * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    if let Err(err) = socket.bind(0) {
+        log_error(&anyhow!("{:?}", err).context("Failed to bind log socket"));
+        return;
+    }
+
+    let mut ticker = Ticker::every(SEND_INTERVAL);
+    loop {
+        ticker.next().await;
+        // Frames wait in the buffer while the network is down, until it fills up
+        stack.wait_config_up().await;
+
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(
+                "Dropped {} log frames that didn't fit the log buffer",
+                dropped
+            );
+        }
+
+        while let Some(datagram) = take_datagram() {
+            // Not logged, that would only queue up more frames. They are still on RTT.
+            if socket.send_to(&datagram, collector).await.is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use crate::{config::parse_u32, sntp};
+
+/// Local hour the nightlight window starts at.
+const FROM_HOUR: u32 = sntp::hour(env!("NIGHTLIGHT_FROM_H"));
+/// Local hour the nightlight window ends at, which can be on the next day.
+const UNTIL_HOUR: u32 = sntp::hour(env!("NIGHTLIGHT_UNTIL_H"));
+const BRIGHTNESS: u32 = {
+    let brightness = parse_u32(env!("NIGHTLIGHT_BRIGHTNESS"));
+    assert!(
+        brightness <= 100,
+        "NIGHTLIGHT_BRIGHTNESS must be at most 100"
+    );
+    brightness
+};
+/// Whether the local time is inside the nightlight window, never before the clock is synced.
+fn active() -> bool {
+    sntp::local_hour().is_some_and(|hour| sntp::in_window(hour, FROM_HOUR, UNTIL_HOUR))
+}
+
+/// Caps the brightness the light turns on at while the nightlight window is active.
+pub fn cap(brightness: u32) -> u32 {
+    if active() {
+        brightness.min(BRIGHTNESS)
+    } else {
+        brightness
+    }
+}
@@ -0,0 +1,460 @@
+use anyhow::{Result, anyhow, bail};
+use defmt::info;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+use esp_hal::{
+    gpio::DriveMode,
+    ledc::{
+        Ledc, LowSpeed,
+        channel::{self, Channel, ChannelHW, ChannelIFace},
+        timer::{self, LSClockSource, TimerIFace, config::Duty},
+    },
+    peripherals::LEDC,
+    time::Rate,
+};
+
+use crate::{
+    config::{parse_u32, str_eq},
+    curve::{self, FRACTION_BITS},
+};
+
+/// How many LEDC channels the light drives: one for a plain dimmable light, or one per
+/// colour of an RGB or RGBW strip.
+pub const CHANNEL_COUNT: usize = if cfg!(feature = "rgbw") {
+    4
+} else if cfg!(feature = "rgb") {
+    3
+} else {
+    1
+};
+
+/// Red, green, blue and then white, of which the first `CHANNEL_COUNT` are used.
+pub const CHANNEL_NUMBERS: [channel::Number; CHANNEL_COUNT] = {
+    let all = [
+        channel::Number::Channel0,
+        channel::Number::Channel1,
+        channel::Number::Channel2,
+        channel::Number::Channel3,
+    ];
+
+    let mut numbers = [channel::Number::Channel0; CHANNEL_COUNT];
+    let mut i = 0;
+    while i < CHANNEL_COUNT {
+        numbers[i] = all[i];
+        i += 1;
+    }
+    numbers
+};
+
+/// Red, green and blue, each out of 255.
+pub type Color = [u8; 3];
+pub const WHITE: Color = [255; 3];
+
+/// Source clock of the LEDC timers.
+const APB_CLOCK_HZ: u32 = 80_000_000;
+const TARGET_FREQUENCY_HZ: u32 = 24_000;
+
+/// The LEDC fade step, cycle and scale fields are all 10 bits wide.
+const FADE_FIELD_MAX: u32 = (1 << 10) - 1;
+
+/// Brightness at or below which the PWM drops to the low frequency timer, 0 to disable.
+const LOW_FREQUENCY_BELOW: u32 = parse_u32(env!("PWM_LOW_FREQUENCY_BELOW"));
+/// How far above `LOW_FREQUENCY_BELOW` the brightness must rise to switch back, so a
+/// brightness hovering around the boundary doesn't keep flipping the frequency.
+const LOW_FREQUENCY_HYSTERESIS: u32 = 5;
+
+/// Whether the LED driver expects an inverted signal, so that the output idles high and
+/// 0% brightness holds it high.
+const ACTIVE_LOW: bool = {
+    let polarity = env!("PWM_POLARITY");
+    if str_eq(polarity, "active-high") {
+        false
+    } else if str_eq(polarity, "active-low") {
+        true
+    } else {
+        panic!("PWM_POLARITY must be either active-high or active-low")
+    }
+};
+
+/// New supply compensation factors (per-mille) for the light task to apply.
+pub static COMPENSATION: Signal<CriticalSectionRawMutex, u32> = Signal::new();
+/// New colours for the light task to apply, only sent with the rgb feature.
+pub static COLOR: Signal<CriticalSectionRawMutex, Color> = Signal::new();
+
+/// Splits a colour into how much of each channel's duty it uses, out of 255. An RGBW
+/// strip moves the part shared by all three colours onto its white channel, and a plain
+/// dimmable light ignores the colour.
+fn channel_scales(color: Color) -> [u32; CHANNEL_COUNT] {
+    let [red, green, blue] = color.map(u32::from);
+    let white = red.min(green).min(blue);
+
+    let mut scales = [255; CHANNEL_COUNT];
+    match CHANNEL_COUNT {
+        3 => scales.copy_from_slice(&[red, green, blue]),
+        4 => scales.copy_from_slice(&[red - white, green - white, blue - white, white]),
+        _ => {}
+    }
+    scales
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PwmMode {
+    Normal,
+    /// Fewer switching cycles (so less heat in the driver) and a finer duty resolution
+    /// for the bottom of the dimming range.
+    LowFrequency,
+}
+
+impl PwmMode {
+    pub fn timer_number(self) -> timer::Number {
+        match self {
+            PwmMode::Normal => timer::Number::Timer0,
+            PwmMode::LowFrequency => timer::Number::Timer1,
+        }
+    }
+
+    fn duty(self) -> Duty {
+        match self {
+            // The most resolution that still reaches TARGET_FREQUENCY_HZ from the APB clock,
+            // 12 bits would cap it at 19.5kHz, which some drivers make audible
+            PwmMode::Normal => Duty::Duty11Bit,
+            PwmMode::LowFrequency => Duty::Duty14Bit,
+        }
+    }
+
+    /// Raw duty value at which the output is fully on.
+    fn max_duty(self) -> u32 {
+        1 << self.duty() as u32
+    }
+
+    pub fn timer_config(self) -> timer::config::Config<LSClockSource> {
+        timer::config::Config {
+            duty: self.duty(),
+            clock_source: LSClockSource::APBClk,
+            frequency: match self {
+                // The timer divider can't go below 1, so every extra bit of resolution
+                // halves the highest frequency available
+                PwmMode::Normal => {
+                    Rate::from_hz(TARGET_FREQUENCY_HZ.min(APB_CLOCK_HZ >> self.duty() as u32))
+                }
+                PwmMode::LowFrequency => Rate::from_khz(2),
+            },
+        }
+    }
+}
+
+/// Wraps the LEDC channels driving the light so that a misbehaving peripheral can be
+/// reinitialized instead of leaving the light stuck at its previous level.
+pub struct LightOutput {
+    ledc: &'static Ledc<'static>,
+    timer: &'static timer::Timer<'static, LowSpeed>,
+    low_frequency_timer: &'static timer::Timer<'static, LowSpeed>,
+    channels: &'static mut [Channel<'static, LowSpeed>; CHANNEL_COUNT],
+    mode: PwmMode,
+    /// Brightness with `FRACTION_BITS` fractional bits.
+    level: u32,
+    /// Duty scale factor in per-mille, used to compensate for supply voltage sag.
+    compensation: u32,
+    /// Each channel's share of the duty for the current colour, out of 255.
+    scales: [u32; CHANNEL_COUNT],
+    /// The duties last written, or the one a hardware fade stops at.
+    duty: [u32; CHANNEL_COUNT],
+    /// When the hardware fade in progress is due to finish. The fade registers can only
+    /// express whole steps, so it may stop short of its target slightly early, and the
+    /// output isn't settled until the fade task sets the exact target at this point.
+    fade_end: Option<Instant>,
+}
+
+impl LightOutput {
+    pub fn new(
+        ledc: &'static Ledc<'static>,
+        timer: &'static timer::Timer<'static, LowSpeed>,
+        low_frequency_timer: &'static timer::Timer<'static, LowSpeed>,
+        channels: &'static mut [Channel<'static, LowSpeed>; CHANNEL_COUNT],
+        color: Color,
+    ) -> Self {
+        Self {
+            ledc,
+            timer,
+            low_frequency_timer,
+            channels,
+            mode: PwmMode::Normal,
+            level: 0,
+            compensation: 1000,
+            scales: channel_scales(color),
+            duty: [0; CHANNEL_COUNT],
+            fade_end: None,
+        }
+    }
+
+    fn mode_timer(&self) -> &'static timer::Timer<'static, LowSpeed> {
+        match self.mode {
+            PwmMode::Normal => self.timer,
+            PwmMode::LowFrequency => self.low_frequency_timer,
+        }
+    }
+
+    fn mode_for(&self, brightness: u32) -> PwmMode {
+        match self.mode {
+            PwmMode::Normal if LOW_FREQUENCY_BELOW > 0 && brightness <= LOW_FREQUENCY_BELOW => {
+                PwmMode::LowFrequency
+            }
+            PwmMode::LowFrequency
+                if LOW_FREQUENCY_BELOW == 0
+                    || brightness > LOW_FREQUENCY_BELOW + LOW_FREQUENCY_HYSTERESIS =>
+            {
+                PwmMode::Normal
+            }
+            mode => mode,
+        }
+    }
+
+    pub fn brightness(&self) -> u32 {
+        (self.level + (1 << (FRACTION_BITS - 1))) >> FRACTION_BITS
+    }
+
+    /// Whether a hardware fade is running or hasn't been settled onto its target yet.
+    fn fade_in_progress(&self) -> bool {
+        self.channels[0].is_duty_fade_running()
+            || self.fade_end.is_some_and(|end| Instant::now() < end)
+    }
+
+    /// The brightness currently being output, which trails `brightness` during a hardware fade.
+    pub fn current_brightness(&self) -> u32 {
+        if !self.fade_in_progress() {
+            return self.brightness();
+        }
+
+        // Hardware fades only run on a single channel light, which has no colour scaling
+        let on_duty = self.polarity_adjusted(self.read_duty(0));
+        (0..=100)
+            .rev()
+            .find(|&brightness| self.on_duty_for(brightness << FRACTION_BITS) <= on_duty)
+            .unwrap_or(0)
+    }
+
+    /// Converts between the duty the light is on for and the duty programmed into the
+    /// channel, which are the same unless the output is active-low.
+    fn polarity_adjusted(&self, duty: u32) -> u32 {
+        if ACTIVE_LOW {
+            self.mode.max_duty() - duty
+        } else {
+            duty
+        }
+    }
+
+    fn duty_for(&self, level: u32) -> [u32; CHANNEL_COUNT] {
+        let on_duty = self.on_duty_for(level);
+        self.scales
+            .map(|scale| self.polarity_adjusted(on_duty * scale / 255))
+    }
+
+    /// Maps a brightness level through the dimming curve and supply compensation.
+    fn on_duty_for(&self, level: u32) -> u32 {
+        if level == 0 {
+            return 0;
+        }
+
+        let max_duty = self.mode.max_duty();
+        let duty = curve::interpolate(level) * max_duty / u16::MAX as u32;
+
+        // Keep the lowest levels visible even when the curve rounds them down to nothing
+        (duty * self.compensation / 1000).clamp(1, max_duty)
+    }
+
+    pub fn configure(&mut self, brightness: u32) -> Result<()> {
+        self.level = brightness << FRACTION_BITS;
+        self.configure_channel()
+    }
+
+    fn configure_channel(&mut self) -> Result<()> {
+        let timer = self.mode_timer();
+        for (channel, number) in self.channels.iter_mut().zip(CHANNEL_NUMBERS) {
+            channel
+                .configure(channel::config::Config {
+                    timer,
+                    duty_pct: 0,
+                    drive_mode: DriveMode::PushPull,
+                })
+                .map_err(|err| anyhow!("{:?}", err))?;
+            LEDC::regs()
+                .ch(number as usize)
+                .conf0()
+                .modify(|_, w| w.idle_lv().bit(ACTIVE_LOW));
+        }
+
+        self.set_level(self.level)
+    }
+
+    /// Sets a brightness level with `FRACTION_BITS` fractional bits.
+    pub fn set_level(&mut self, level: u32) -> Result<()> {
+        let mode = self.mode_for(level >> FRACTION_BITS);
+        if mode != self.mode {
+            self.mode = mode;
+            self.level = level;
+            info!("Switching PWM to {} mode", mode);
+
+            // Rebinding the channels to the other timer also applies the level
+            return self.configure_channel();
+        }
+
+        let duty = self.duty_for(level);
+        for (channel, duty) in self.channels.iter_mut().zip(duty) {
+            channel.set_duty_hw(duty);
+        }
+
+        self.level = level;
+        self.duty = duty;
+        self.fade_end = None;
+
+        Ok(())
+    }
+
+    /// Programs the LEDC fade registers to ramp from the current output to `brightness`,
+    /// leaving the hardware to step the duty without any CPU involvement.
+    pub fn start_fade(&mut self, brightness: u32, duration: Duration) -> Result<()> {
+        // Each channel would fade over a different number of steps and fall out of
+        // step with the others, so colour changes are stepped in software instead
+        if CHANNEL_COUNT > 1 {
+            bail!("Hardware fades only drive a single channel");
+        }
+
+        let from = if self.channels[0].is_duty_fade_running() {
+            self.read_duty(0)
+        } else {
+            self.duty[0]
+        };
+        let to = self.duty_for(brightness << FRACTION_BITS)[0];
+
+        let duty_diff = from.abs_diff(to);
+        let pwm_cycles = duration.as_millis() * self.mode_timer().frequency() as u64 / 1000;
+        if duty_diff == 0 || pwm_cycles == 0 {
+            bail!("Nothing to fade");
+        }
+
+        let duty_per_cycle = duty_diff.div_ceil(FADE_FIELD_MAX);
+        let duty_steps = duty_diff / duty_per_cycle;
+        let cycles_per_step = pwm_cycles / duty_steps as u64;
+        if !(1..=FADE_FIELD_MAX as u64).contains(&cycles_per_step) {
+            bail!(
+                "Fade of {} duty over {} PWM cycles doesn't fit the fade registers",
+                duty_diff,
+                pwm_cycles
+            );
+        }
+
+        self.channels[0].start_duty_fade_hw(
+            from,
+            to > from,
+            duty_steps as u16,
+            cycles_per_step as u16,
+            duty_per_cycle as u16,
+        );
+
+        self.level = brightness << FRACTION_BITS;
+        // Whole steps can fall short of the target, the fade task settles the rest
+        let reached = duty_steps * duty_per_cycle;
+        self.duty[0] = if to > from {
+            from + reached
+        } else {
+            from - reached
+        };
+        self.fade_end = Some(Instant::now() + duration);
+
+        Ok(())
+    }
+
+    /// Rescales the output for a new supply compensation factor, keeping the brightness.
+    pub fn set_compensation(&mut self, compensation: u32) -> Result<()> {
+        self.compensation = compensation;
+
+        // A running fade picks up the new factor when it settles
+        if self.channels[0].is_duty_fade_running() {
+            return Ok(());
+        }
+
+        self.set_level(self.level)
+    }
+
+    /// Switches to a new colour at the current brightness.
+    pub fn set_color(&mut self, color: Color) -> Result<()> {
+        self.scales = channel_scales(color);
+        self.set_level(self.level)
+    }
+
+    /// Reprograms the timer and channel registers from scratch, then applies the brightness.
+    pub fn reinitialize(&mut self) -> Result<()> {
+        // The channel keeps its reference to the original timer handles, fresh handles
+        // to the same hardware timers are only needed to rewrite their registers.
+        for mode in [PwmMode::Normal, PwmMode::LowFrequency] {
+            self.ledc
+                .timer::<LowSpeed>(mode.timer_number())
+                .configure(mode.timer_config())
+                .map_err(|err| anyhow!("{:?}", err))?;
+        }
+
+        self.configure_channel()?;
+
+        info!("LEDC output reinitialized");
+
+        Ok(())
+    }
+
+    fn read_duty(&self, index: usize) -> u32 {
+        // The duty registers hold 4 fractional bits below the integer duty value
+        LEDC::regs()
+            .ch(CHANNEL_NUMBERS[index] as usize)
+            .duty_r()
+            .read()
+            .duty_r()
+            .bits()
+            >> 4
+    }
+
+    /// Cross-checks the commanded output against each channel's registers: the timer it
+    /// is bound to, its hpoint (always 0, the output goes high at the start of each
+    /// period) and its duty.
+    pub fn verify(&self) -> Result<()> {
+        for (index, number) in CHANNEL_NUMBERS.into_iter().enumerate() {
+            let channel = LEDC::regs().ch(number as usize);
+
+            let timer = channel.conf0().read().timer_sel().bits();
+            if timer != self.mode.timer_number() as u8 {
+                bail!(
+                    "LEDC channel {} is bound to timer {} but {} mode uses timer {}",
+                    index,
+                    timer,
+                    self.mode,
+                    self.mode.timer_number() as u8
+                );
+            }
+
+            let hpoint = channel.hpoint().read().hpoint().bits();
+            if hpoint != 0 {
+                bail!(
+                    "LEDC channel {} hpoint register is {} but 0 was configured",
+                    index,
+                    hpoint
+                );
+            }
+
+            // The duty register is expected to move until a hardware fade has been settled
+            if self.fade_in_progress() {
+                continue;
+            }
+
+            let actual = self.read_duty(index);
+
+            if actual != self.duty[index] {
+                bail!(
+                    "LEDC channel {} duty register is {} but {} was configured",
+                    index,
+                    actual,
+                    self.duty[index]
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
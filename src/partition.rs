@@ -0,0 +1,62 @@
+use alloc::vec;
+use anyhow::{Result, anyhow, bail};
+use embedded_storage::ReadStorage;
+use esp_storage::FlashStorage;
+
+/// Where the bootloader reads the partition table from.
+const TABLE_OFFSET: u32 = 0x8000;
+const TABLE_LEN: usize = 0xc00;
+const ENTRY_LEN: usize = 32;
+/// `0x50aa`, little-endian.
+const MAGIC: [u8; 2] = [0xaa, 0x50];
+const TYPE_DATA: u8 = 0x01;
+
+/// One of the firmware's own data partitions from `partitions.csv`, each with a custom
+/// subtype so nothing else claims it.
+pub struct Partition {
+    pub label: &'static str,
+    pub subtype: u8,
+    /// How much of the partition is used, which it has to be at least as large as.
+    pub len: usize,
+}
+
+impl Partition {
+    /// Looks the partition up in the partition table, returning its offset.
+    pub fn find(&self, flash: &mut FlashStorage<'static>) -> Result<u32> {
+        let mut table = vec![0; TABLE_LEN];
+        flash
+            .read(TABLE_OFFSET, &mut table)
+            .map_err(|err| anyhow!("{:?}", err))?;
+
+        for entry in table.chunks_exact(ENTRY_LEN) {
+            // The entries end at the table's checksum entry or erased flash
+            if entry[0..2] != MAGIC {
+                break;
+            }
+
+            let label = &entry[12..28];
+            let label = &label[..label.iter().position(|&b| b == 0).unwrap_or(label.len())];
+            if entry[2] != TYPE_DATA || entry[3] != self.subtype || label != self.label.as_bytes() {
+                continue;
+            }
+
+            let offset = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+            let size = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+            if (size as usize) < self.len {
+                bail!(
+                    "The {} partition is {} bytes, less than the {} it needs",
+                    self.label,
+                    size,
+                    self.len
+                );
+            }
+
+            return Ok(offset);
+        }
+
+        bail!(
+            "No {} partition in the partition table, flash it with partitions.csv",
+            self.label
+        )
+    }
+}
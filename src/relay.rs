@@ -0,0 +1,44 @@
+use defmt::info;
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::Output;
+
+use crate::config::parse_u32;
+
+/// How long the light has to stay off before the LED driver is disconnected.
+pub const OFF_DELAY: Duration =
+    Duration::from_secs(parse_u32(env!("STANDBY_RELAY_OFF_DELAY_S")) as u64);
+
+/// How long the LED driver needs after being reconnected before it can follow the PWM.
+const SETTLE_DELAY: Duration =
+    Duration::from_millis(parse_u32(env!("STANDBY_RELAY_SETTLE_MS")) as u64);
+
+/// Relay (active-high) that fully disconnects the LED driver to eliminate its standby draw.
+pub struct StandbyRelay {
+    pin: Output<'static>,
+}
+
+impl StandbyRelay {
+    pub fn new(pin: Output<'static>) -> Self {
+        Self { pin }
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.pin.is_set_high()
+    }
+
+    pub async fn engage(&mut self) {
+        if self.is_engaged() {
+            return;
+        }
+
+        self.pin.set_high();
+        info!("Standby relay engaged");
+
+        Timer::after(SETTLE_DELAY).await;
+    }
+
+    pub fn disengage(&mut self) {
+        self.pin.set_low();
+        info!("Standby relay disengaged");
+    }
+}
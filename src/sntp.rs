@@ -0,0 +1,200 @@
+use core::{cell::Cell, net::Ipv4Addr, str::FromStr};
+
+use anyhow::{Result, anyhow, bail};
+use defmt::info;
+use embassy_net::{
+    IpAddress, IpEndpoint, Stack,
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+
+use crate::{
+    config::{parse_i32, parse_u32},
+    log_error,
+};
+
+const SERVER: &str = env!("SNTP_SERVER");
+const SYNC_INTERVAL: Duration =
+    Duration::from_secs(parse_u32(env!("SNTP_SYNC_INTERVAL_MIN")) as u64 * 60);
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NTP_PORT: u16 = 123;
+const PACKET_LEN: usize = 48;
+/// Version 4, client mode.
+const CLIENT_HEADER: u8 = (4 << 3) | 3;
+const MODE_SERVER: u8 = 4;
+
+/// Seconds from the NTP epoch (1900) to the unix epoch (1970).
+const UNIX_EPOCH_NTP_SECS: u64 = 2_208_988_800;
+
+/// Offset of local time from UTC, daylight saving isn't followed.
+const UTC_OFFSET_MIN: i64 = parse_i32(env!("UTC_OFFSET_MIN")) as i64;
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// Unix time in microseconds at `Instant` zero, `None` until the first sync.
+static UTC_AT_BOOT_US: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> =
+    Mutex::new(Cell::new(None));
+
+/// Converts a monotonic instant to unix time in microseconds, once the clock is synced.
+pub fn utc_at(instant: Instant) -> Option<u64> {
+    UTC_AT_BOOT_US
+        .lock(Cell::get)
+        .map(|at_boot| at_boot + instant.as_micros())
+}
+
+/// The current unix time in microseconds, once the clock is synced.
+pub fn now_utc() -> Option<u64> {
+    utc_at(Instant::now())
+}
+
+/// Parses a local hour of the day from a compile-time environment variable.
+pub const fn hour(value: &str) -> u32 {
+    let hour = parse_u32(value);
+    assert!(hour < 24, "Hours must be 0 to 23");
+    hour
+}
+
+/// The current local hour, once the clock is synced.
+pub fn local_hour() -> Option<u32> {
+    let local_minute =
+        (now_utc()? as i64 / 60_000_000 + UTC_OFFSET_MIN).rem_euclid(MINUTES_PER_DAY);
+    Some((local_minute / 60) as u32)
+}
+
+/// Whether `hour` is in the window starting at `from` and ending at `until`, which can
+/// be on the next day.
+pub fn in_window(hour: u32, from: u32, until: u32) -> bool {
+    if from <= until {
+        (from..until).contains(&hour)
+    } else {
+        hour >= from || hour < until
+    }
+}
+
+/// Reads an NTP timestamp as unix time in microseconds.
+fn read_timestamp(packet: &[u8], offset: usize) -> u64 {
+    let mut secs = u32::from_be_bytes(packet[offset..offset + 4].try_into().unwrap()) as u64;
+    let fraction = u32::from_be_bytes(packet[offset + 4..offset + 8].try_into().unwrap()) as u64;
+
+    // Timestamps before the unix epoch are from after the 2036 rollover
+    if secs < UNIX_EPOCH_NTP_SECS {
+        secs += 1 << 32;
+    }
+
+    (secs - UNIX_EPOCH_NTP_SECS) * 1_000_000 + ((fraction * 1_000_000) >> 32)
+}
+
+async fn resolve_server(stack: Stack<'static>) -> Result<Ipv4Addr> {
+    if let Ok(ip) = Ipv4Addr::from_str(SERVER) {
+        return Ok(ip);
+    }
+
+    let addrs = stack
+        .dns_query(SERVER, DnsQueryType::A)
+        .await
+        .map_err(|err| anyhow!("{:?}", err))?;
+    match addrs.first() {
+        Some(IpAddress::Ipv4(ip)) => Ok(*ip),
+        _ => bail!("No addresses found for {}", SERVER),
+    }
+}
+
+/// Queries the server once, returning the unix time at `Instant` zero.
+async fn query(stack: Stack<'static>, socket: &mut UdpSocket<'_>) -> Result<u64> {
+    let server = IpEndpoint::new(IpAddress::Ipv4(resolve_server(stack).await?), NTP_PORT);
+
+    // The transmit timestamp is only echoed back, so the send time is used to match
+    // the response to this request
+    let sent_at = Instant::now();
+    let mut request = [0; PACKET_LEN];
+    request[0] = CLIENT_HEADER;
+    request[40..48].copy_from_slice(&sent_at.as_micros().to_be_bytes());
+
+    socket
+        .send_to(&request, server)
+        .await
+        .map_err(|err| anyhow!("{:?}", err))?;
+
+    let mut response = [0; PACKET_LEN];
+    loop {
+        let (len, from) = with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut response))
+            .await
+            .map_err(|_| anyhow!("No response from {}", SERVER))?
+            .map_err(|err| anyhow!("{:?}", err))?;
+        let received_at = Instant::now();
+
+        if from.endpoint != server || len < PACKET_LEN || response[24..32] != request[40..48] {
+            continue;
+        }
+        // Stratum 0 is a kiss-o'-death, telling clients to back off
+        if response[0] & 0x07 != MODE_SERVER || response[1] == 0 {
+            bail!("{} refused the request", SERVER);
+        }
+
+        let server_received = read_timestamp(&response, 32);
+        let server_sent = read_timestamp(&response, 40);
+
+        // Half of the round trip, less the time the server held the request, is assumed
+        // to be the time the response took to arrive
+        let round_trip = (received_at - sent_at).as_micros();
+        let server_delay = server_sent.saturating_sub(server_received);
+        let utc_now = server_sent + round_trip.saturating_sub(server_delay) / 2;
+
+        // A time before boot can only come from a bad or forged response
+        return utc_now
+            .checked_sub(received_at.as_micros())
+            .ok_or_else(|| anyhow!("{} sent a time before the device booted", SERVER));
+    }
+}
+
+/// Keeps wall-clock time synced over SNTP, for anything that needs to timestamp or
+/// schedule in real time.
+#[embassy_executor::task]
+pub async fn sntp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 256];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 256];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    if let Err(err) = socket.bind(0) {
+        log_error(&anyhow!("{:?}", err).context("Failed to bind SNTP socket"));
+        return;
+    }
+
+    loop {
+        // Syncs are held off while the network is down rather than failing repeatedly
+        stack.wait_config_up().await;
+
+        match query(stack, &mut socket).await {
+            Ok(utc_at_boot) => {
+                let previous = UTC_AT_BOOT_US.lock(|cell| cell.replace(Some(utc_at_boot)));
+                match previous {
+                    Some(previous) => info!(
+                        "Clock synced, corrected by {}us",
+                        utc_at_boot as i64 - previous as i64
+                    ),
+                    None => info!(
+                        "Clock synced, unix time is {}s",
+                        now_utc().unwrap() / 1_000_000
+                    ),
+                }
+
+                Timer::after(SYNC_INTERVAL).await;
+            }
+            Err(err) => {
+                log_error(&err.context("Failed to sync clock"));
+                Timer::after(RETRY_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+/// Current connection state or most recent failure class, shown on the status LED so an
+/// installer can tell what is going on without any tools.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Diagnostic {
+    /// Associating with the wifi network.
+    Associating,
+    /// On the network, waiting for the server connection.
+    Connecting,
+    Connected,
+    NoWifi,
+    NoIp,
+    NoDns,
+    ServerError,
+    /// The config can't work, so the network is never started.
+    BadConfig,
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(not(feature = "status-led"), allow(dead_code))]
+enum Pattern {
+    /// Even blinking, with the on and off time in milliseconds.
+    Blink(u64),
+    /// A short flash every few seconds.
+    Heartbeat,
+    /// Failures are counted out in blinks.
+    Code(usize),
+}
+
+impl Diagnostic {
+    #[cfg_attr(not(feature = "status-led"), allow(dead_code))]
+    fn pattern(self) -> Pattern {
+        match self {
+            Diagnostic::Associating => Pattern::Blink(100),
+            Diagnostic::Connecting => Pattern::Blink(500),
+            Diagnostic::Connected => Pattern::Heartbeat,
+            Diagnostic::NoWifi => Pattern::Code(1),
+            Diagnostic::NoIp => Pattern::Code(2),
+            Diagnostic::NoDns => Pattern::Code(3),
+            Diagnostic::ServerError => Pattern::Code(4),
+            Diagnostic::BadConfig => Pattern::Code(5),
+        }
+    }
+}
+
+static DIAGNOSTIC: Signal<CriticalSectionRawMutex, Diagnostic> = Signal::new();
+
+pub fn report(diagnostic: Diagnostic) {
+    DIAGNOSTIC.signal(diagnostic);
+}
+
+#[cfg(feature = "status-led")]
+mod led {
+    use embassy_futures::select::{Either, select};
+    use embassy_time::{Duration, Timer};
+    use esp_hal::gpio::Output;
+
+    use super::{DIAGNOSTIC, Diagnostic, Pattern};
+
+    const BLINK_ON: Duration = Duration::from_millis(200);
+    const BLINK_OFF: Duration = Duration::from_millis(300);
+    const CODE_PAUSE: Duration = Duration::from_millis(1500);
+    const HEARTBEAT_ON: Duration = Duration::from_millis(50);
+    const HEARTBEAT_OFF: Duration = Duration::from_millis(3000);
+
+    /// The status LED is wired active-low.
+    fn set_led(led: &mut Output<'static>, on: bool) {
+        led.set_level((!on).into());
+    }
+
+    async fn flash(led: &mut Output<'static>, on: Duration, off: Duration) {
+        set_led(led, true);
+        Timer::after(on).await;
+        set_led(led, false);
+        Timer::after(off).await;
+    }
+
+    /// Shows one cycle of a pattern.
+    async fn show(led: &mut Output<'static>, pattern: Pattern) {
+        match pattern {
+            Pattern::Blink(ms) => {
+                flash(led, Duration::from_millis(ms), Duration::from_millis(ms)).await
+            }
+            Pattern::Heartbeat => flash(led, HEARTBEAT_ON, HEARTBEAT_OFF).await,
+            Pattern::Code(count) => {
+                for _ in 0..count {
+                    flash(led, BLINK_ON, BLINK_OFF).await;
+                }
+                Timer::after(CODE_PAUSE).await;
+            }
+        }
+    }
+
+    #[embassy_executor::task]
+    pub async fn status_led_task(mut led: Output<'static>) {
+        let mut pattern = Diagnostic::Associating.pattern();
+
+        loop {
+            if let Either::Second(diagnostic) =
+                select(show(&mut led, pattern), DIAGNOSTIC.wait()).await
+            {
+                set_led(&mut led, false);
+                pattern = diagnostic.pattern();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "status-led")]
+pub use led::status_led_task;
@@ -0,0 +1,264 @@
+use core::future::pending;
+
+use anyhow::{Result, anyhow};
+use defmt::{info, warn};
+use embassy_futures::select::{Either4, select4};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+
+use crate::{
+    config::{self, parse_u32, str_eq},
+    journal::{self, Journal},
+    log_error,
+    output::{Color, WHITE},
+    partition::Partition,
+};
+
+const MAGIC: [u8; 4] = *b"DCTL";
+/// Version 2 added the colour, older records are replaced by the defaults.
+const VERSION: u8 = 2;
+const RECORD_LEN: usize = 16;
+
+const PARTITION: Partition = Partition {
+    label: "devctl_set",
+    subtype: 0x41,
+    len: RECORD_LEN,
+};
+
+/// Saves are held back until settings stop changing for this long, so dragging a
+/// brightness slider doesn't wear out the flash.
+const SAVE_DEBOUNCE: Duration = Duration::from_secs(5);
+
+static PENDING: Signal<CriticalSectionRawMutex, Settings> = Signal::new();
+static FACTORY_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub brightness: u32,
+    /// Brightness restored when the light is turned back on.
+    pub last_brightness: u32,
+    /// Only changed with the rgb feature, a plain dimmable light stays white.
+    pub color: Color,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            brightness: 100,
+            last_brightness: 100,
+            color: WHITE,
+        }
+    }
+}
+
+/// What the light does when power returns after an interruption.
+#[derive(Clone, Copy)]
+pub enum PowerOnBehavior {
+    /// Come back at the brightness it had before losing power.
+    Restore,
+    Off,
+    On {
+        brightness: u32,
+    },
+}
+
+pub const POWER_ON_BEHAVIOR: PowerOnBehavior = {
+    let behavior = env!("POWER_ON_BEHAVIOR");
+    if str_eq(behavior, "restore") {
+        PowerOnBehavior::Restore
+    } else if str_eq(behavior, "off") {
+        PowerOnBehavior::Off
+    } else if str_eq(behavior, "on") {
+        let brightness = parse_u32(env!("POWER_ON_BRIGHTNESS"));
+        assert!(brightness <= 100, "POWER_ON_BRIGHTNESS must be at most 100");
+        PowerOnBehavior::On { brightness }
+    } else {
+        panic!("POWER_ON_BEHAVIOR must be one of restore, off or on")
+    }
+};
+
+impl PowerOnBehavior {
+    /// Picks the settings to boot with given the settings saved before power was lost.
+    pub fn apply(self, saved: Settings) -> Settings {
+        match self {
+            PowerOnBehavior::Restore => saved,
+            PowerOnBehavior::Off => Settings {
+                brightness: 0,
+                ..saved
+            },
+            PowerOnBehavior::On { brightness } => Settings {
+                brightness,
+                last_brightness: brightness,
+                ..saved
+            },
+        }
+    }
+}
+
+/// FNV-1a, only used to detect torn or never-written records.
+pub fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+impl Settings {
+    fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut record = [0; RECORD_LEN];
+        record[0..4].copy_from_slice(&MAGIC);
+        record[4] = VERSION;
+        record[5] = self.brightness as u8;
+        record[6] = self.last_brightness as u8;
+        record[7..10].copy_from_slice(&self.color);
+
+        let checksum = checksum(&record[..RECORD_LEN - 4]);
+        record[RECORD_LEN - 4..].copy_from_slice(&checksum.to_le_bytes());
+
+        record
+    }
+
+    fn decode(record: &[u8; RECORD_LEN]) -> Option<Self> {
+        let (body, stored_checksum) = record.split_at(RECORD_LEN - 4);
+        if body[0..4] != MAGIC || body[4] != VERSION {
+            return None;
+        }
+        if stored_checksum != checksum(body).to_le_bytes() {
+            return None;
+        }
+
+        Some(Self {
+            brightness: (body[5] as u32).min(100),
+            last_brightness: (body[6] as u32).min(100),
+            color: [body[7], body[8], body[9]],
+        })
+    }
+}
+
+pub struct SettingsStore {
+    flash: FlashStorage<'static>,
+    /// Where the settings partition starts, `None` if the partition table doesn't have one.
+    offset: Option<u32>,
+}
+
+impl SettingsStore {
+    pub fn new(mut flash: FlashStorage<'static>) -> Self {
+        let offset = match PARTITION.find(&mut flash) {
+            Ok(offset) => Some(offset),
+            Err(err) => {
+                log_error(&err.context("Failed to find settings partition"));
+                None
+            }
+        };
+
+        Self { flash, offset }
+    }
+
+    fn offset(&self) -> Result<u32> {
+        self.offset
+            .ok_or_else(|| anyhow!("No {} partition to keep settings in", PARTITION.label))
+    }
+
+    /// The flash is shared with the error journal and the runtime config.
+    pub fn flash(&mut self) -> &mut FlashStorage<'static> {
+        &mut self.flash
+    }
+
+    pub fn load(&mut self) -> Result<Option<Settings>> {
+        let mut record = [0; RECORD_LEN];
+        let offset = self.offset()?;
+        self.flash
+            .read(offset, &mut record)
+            .map_err(|err| anyhow!("{:?}", err))?;
+
+        Ok(Settings::decode(&record))
+    }
+
+    fn save(&mut self, settings: &Settings) -> Result<()> {
+        let offset = self.offset()?;
+        self.flash
+            .write(offset, &settings.encode())
+            .map_err(|err| anyhow!("{:?}", err))
+    }
+
+    /// Overwrites the record with erased flash, so the defaults are loaded on next boot.
+    fn erase(&mut self) -> Result<()> {
+        let offset = self.offset()?;
+        self.flash
+            .write(offset, &[0xff; RECORD_LEN])
+            .map_err(|err| anyhow!("{:?}", err))
+    }
+}
+
+/// Queues settings to be written to flash once they have settled.
+pub fn save(settings: Settings) {
+    PENDING.signal(settings);
+}
+
+/// Erases the saved settings and reboots, dropping any save still pending.
+#[cfg_attr(not(feature = "button"), allow(dead_code))]
+pub fn factory_reset() {
+    FACTORY_RESET.signal(());
+}
+
+/// Owns the flash, writing settings once they settle and journal events straight away.
+#[embassy_executor::task]
+pub async fn storage_task(mut store: SettingsStore, mut saved: Settings, mut journal: Journal) {
+    // Settings waiting out the debounce, and when they are due to be written
+    let mut unsaved: Option<(Settings, Instant)> = None;
+
+    loop {
+        let save_due = async {
+            match unsaved {
+                Some((_, due)) => Timer::at(due).await,
+                None => pending().await,
+            }
+        };
+
+        let event = select4(
+            PENDING.wait(),
+            save_due,
+            journal::next_event(),
+            FACTORY_RESET.wait(),
+        )
+        .await;
+
+        match event {
+            Either4::First(settings) => {
+                unsaved = Some((settings, Instant::now() + SAVE_DEBOUNCE));
+            }
+            Either4::Second(()) => {
+                let Some((settings, _)) = unsaved.take() else {
+                    continue;
+                };
+                if settings == saved {
+                    continue;
+                }
+
+                match store.save(&settings) {
+                    Ok(()) => {
+                        info!("Saved settings to flash");
+                        saved = settings;
+                    }
+                    Err(err) => log_error(&err.context("Failed to save settings")),
+                }
+            }
+            Either4::Third(event) => {
+                if let Err(err) = journal.append(store.flash(), event) {
+                    log_error(&err.context("Failed to write error journal"));
+                }
+            }
+            Either4::Fourth(()) => {
+                warn!("Factory reset, erasing settings and config and rebooting");
+                if let Err(err) = store.erase() {
+                    log_error(&err.context("Failed to erase settings"));
+                }
+                if let Err(err) = config::erase_runtime(store.flash()) {
+                    log_error(&err.context("Failed to erase runtime config"));
+                }
+                esp_hal::system::software_reset();
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use defmt::{info, warn};
+use embassy_time::{Duration, Ticker};
+use esp_hal::{
+    analog::adc::AdcPin,
+    peripherals::{ADC1, GPIO2},
+};
+
+use crate::{
+    analog::{self, SharedAdc},
+    config::parse_u32,
+    journal::{self, Event},
+    output::COMPENSATION,
+};
+
+const NOMINAL_MV: u32 = parse_u32(env!("SUPPLY_NOMINAL_MV"));
+const UNDERVOLTAGE_MV: u32 = parse_u32(env!("SUPPLY_UNDERVOLTAGE_MV"));
+/// Ratio of the resistor divider between the supply rail and the ADC pin.
+const DIVIDER_RATIO: u32 = parse_u32(env!("SUPPLY_DIVIDER_RATIO"));
+
+/// Approximate full scale input of the ADC at 11dB attenuation.
+const ADC_FULL_SCALE_MV: u32 = 3100;
+const ADC_MAX: u32 = 4095;
+
+/// Caps how hard a sagging rail is compensated so a failing supply isn't driven further.
+const MAX_COMPENSATION: u32 = 1500;
+/// Changes smaller than this (per-mille) aren't worth rewriting the duty for.
+const COMPENSATION_HYSTERESIS: u32 = 10;
+
+fn compensation_for(supply_mv: u32) -> u32 {
+    (NOMINAL_MV * 1000 / supply_mv.max(1)).min(MAX_COMPENSATION)
+}
+
+#[embassy_executor::task]
+pub async fn supply_task(adc: &'static SharedAdc, mut pin: AdcPin<GPIO2<'static>, ADC1<'static>>) {
+    let mut ticker = Ticker::every(Duration::from_millis(250));
+
+    let mut filtered_mv = NOMINAL_MV;
+    let mut applied_compensation = 1000;
+    let mut undervoltage = false;
+
+    loop {
+        ticker.next().await;
+
+        let Some(raw) = analog::read(adc, &mut pin) else {
+            continue;
+        };
+
+        let supply_mv = raw as u32 * ADC_FULL_SCALE_MV / ADC_MAX * DIVIDER_RATIO;
+        filtered_mv = (filtered_mv * 7 + supply_mv) / 8;
+
+        if filtered_mv < UNDERVOLTAGE_MV && !undervoltage {
+            warn!("Supply undervoltage: {}mV", filtered_mv);
+            journal::record(Event::SupplyUndervoltage);
+            undervoltage = true;
+        } else if filtered_mv >= UNDERVOLTAGE_MV && undervoltage {
+            info!("Supply voltage recovered: {}mV", filtered_mv);
+            undervoltage = false;
+        }
+
+        let compensation = compensation_for(filtered_mv);
+        if compensation.abs_diff(applied_compensation) >= COMPENSATION_HYSTERESIS {
+            applied_compensation = compensation;
+            COMPENSATION.signal(compensation);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use defmt::error;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, with_timeout};
+use esp_hal::gpio::Output;
+
+use crate::journal::{self, Event};
+
+/// How often the light task cross-checks its output against the hardware.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the watchguard tolerates not hearing from the light task.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CHECK_RESULTS: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+pub fn report_check(passed: bool) {
+    CHECK_RESULTS.signal(passed);
+}
+
+/// Trips when an output check fails or the light task stops reporting. If a kill switch
+/// is wired (an active-high enable line on the LED driver) it is latched low until reboot.
+#[embassy_executor::task]
+pub async fn watchguard_task(mut kill_switch: Option<Output<'static>>) {
+    loop {
+        match with_timeout(CHECK_TIMEOUT, CHECK_RESULTS.wait()).await {
+            Ok(true) => continue,
+            Ok(false) => {
+                error!("Light output check failed!");
+                journal::record(Event::OutputCheckFailed);
+            }
+            Err(_) => {
+                error!("Light task stopped reporting output checks!");
+                journal::record(Event::OutputStalled);
+            }
+        }
+
+        if let Some(kill_switch) = &mut kill_switch {
+            kill_switch.set_low();
+            error!("Kill switch engaged, LED driver disabled until reboot");
+            return;
+        }
+    }
+}
@@ -1,11 +1,26 @@
 use anyhow::{Result, anyhow};
-use defmt::{error, info};
-use embassy_time::{Duration, Timer};
+use defmt::{error, info, warn};
+use embassy_time::{Duration, Timer, with_timeout};
 use esp_radio::wifi::{
     ClientConfig, ModeConfig, PowerSaveMode, WifiController, WifiEvent, WifiStaState,
 };
 
-use crate::log_error;
+use crate::{
+    config, log_error,
+    status::{self, Diagnostic},
+};
+
+const RSSI_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// Signal below which the connection tends to drop packets and reconnect.
+const WEAK_RSSI_DBM: i32 = -75;
+
+fn log_rssi(controller: &WifiController<'static>) {
+    match controller.rssi() {
+        Ok(rssi) if rssi < WEAK_RSSI_DBM => warn!("Weak wifi signal: {}dBm", rssi),
+        Ok(rssi) => info!("Wifi signal: {}dBm", rssi),
+        Err(err) => log_error(&anyhow!("{:?}", err).context("Failed to read wifi RSSI")),
+    }
+}
 
 #[embassy_executor::task]
 pub async fn wifi_connection(mut controller: WifiController<'static>) {
@@ -22,15 +37,26 @@ pub async fn wifi_connection(mut controller: WifiController<'static>) {
 
 async fn run_wifi_loop(controller: &mut WifiController<'static>) -> Result<()> {
     if esp_radio::wifi::sta_state() == WifiStaState::Connected {
-        controller.wait_for_event(WifiEvent::StaDisconnected).await;
+        // Sample the signal while waiting, so marginal placements show up in the logs
+        while with_timeout(
+            RSSI_SAMPLE_INTERVAL,
+            controller.wait_for_event(WifiEvent::StaDisconnected),
+        )
+        .await
+        .is_err()
+            && esp_radio::wifi::sta_state() == WifiStaState::Connected
+        {
+            log_rssi(controller);
+        }
+        status::report(Diagnostic::Associating);
         Timer::after(Duration::from_millis(5000)).await
     }
 
     if !matches!(controller.is_started(), Ok(true)) {
         let client_config = ModeConfig::Client(
             ClientConfig::default()
-                .with_ssid(env!("WIFI_SSID").into())
-                .with_password(env!("WIFI_PASSWORD").into()),
+                .with_ssid(config::runtime().wifi_ssid.clone())
+                .with_password(config::runtime().wifi_password.clone()),
         );
 
         controller
@@ -44,9 +70,14 @@ async fn run_wifi_loop(controller: &mut WifiController<'static>) -> Result<()> {
     }
 
     match controller.connect_async().await {
-        Ok(_) => info!("Wifi connected!"),
+        Ok(_) => {
+            info!("Wifi connected!");
+            log_rssi(controller);
+            status::report(Diagnostic::Connecting);
+        }
         Err(e) => {
             error!("Failed to connect to wifi: {:?}", e);
+            status::report(Diagnostic::NoWifi);
             Timer::after(Duration::from_millis(5000)).await
         }
     }